@@ -0,0 +1,98 @@
+//! Benchmarks for the descriptor comparisons in the inner loop of
+//! a full-image scan.
+//!
+//! discrepancy relies on u64::count_ones lowering to a single popcnt
+//! instruction. That is only the case when the target supports it, so
+//! run these with e.g. RUSTFLAGS="-C target-cpu=native" to measure the
+//! code that will run in practice. Without popcnt both versions fall
+//! back to a bit-twiddling sequence and are several times slower.
+//! On x86_64 the "popcnt" benchmark compiles the same comparison with
+//! popcnt enabled, when the CPU supports it, so that the difference is
+//! visible without changing RUSTFLAGS.
+
+extern crate criterion;
+extern crate rand;
+extern crate vision;
+
+use criterion::{black_box,criterion_group,criterion_main,Criterion};
+use rand::{Rng,SeedableRng};
+use rand::rngs::StdRng;
+use vision::{discrepancy,discrepancy_words,quantise_patch,PatchDescriptor,PatchModel};
+
+/// Quantised random patches. Like any quantised patch these have
+/// exactly one bin set at each location.
+fn random_patches(rng: &mut StdRng, count: usize) -> Vec<PatchDescriptor> {
+    (0..count).map(|_| {
+        let mut sample = [0u8; 64];
+        rng.fill(&mut sample[..]);
+        quantise_patch(&sample)
+    }).collect()
+}
+
+/// A model trained on a handful of noisy copies of one patch, so that
+/// most bins are rare as in a model trained on real data.
+fn trained_model(rng: &mut StdRng) -> PatchDescriptor {
+    let mut base = [0u8; 64];
+    rng.fill(&mut base[..]);
+    let mut model = PatchModel::new();
+    for _ in 0..20 {
+        let mut sample = base;
+        for p in sample.iter_mut() {
+            *p = p.saturating_add(rng.gen_range(0..16));
+        }
+        model.add_normalised_sample(sample);
+    }
+    model.quantise()
+}
+
+/// The straightforward loop over bits which discrepancy replaces,
+/// kept as a baseline.
+fn discrepancy_naive(patch: &PatchDescriptor, model: &PatchDescriptor) -> u16 {
+    let mut count = 0u16;
+    for i in 0..5 {
+        let both = patch[i] & model[i];
+        for bit in 0..64 {
+            count += ((both >> bit) & 1) as u16;
+        }
+    }
+    count
+}
+
+/// discrepancy compiled with popcnt enabled regardless of the target
+/// flags. Callers must check that the CPU supports popcnt.
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "popcnt")]
+unsafe fn discrepancy_popcnt(patch: &PatchDescriptor, model: &PatchDescriptor) -> u16 {
+    patch.iter().zip(model.iter())
+        .map(|(p, m)| (p & m).count_ones() as u16)
+        .sum()
+}
+
+fn bench_discrepancy(c: &mut Criterion) {
+    let mut rng = StdRng::seed_from_u64(87);
+    let model = trained_model(&mut rng);
+    let patches = random_patches(&mut rng, 1024);
+
+    let mut group = c.benchmark_group("discrepancy");
+    group.bench_function("unrolled", |b| b.iter(|| {
+        patches.iter().map(|p| discrepancy(black_box(p), black_box(&model)) as u32).sum::<u32>()
+    }));
+    group.bench_function("words", |b| b.iter(|| {
+        patches.iter().map(|p| discrepancy_words(black_box(p), black_box(&model)) as u32).sum::<u32>()
+    }));
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("popcnt") {
+            group.bench_function("popcnt", |b| b.iter(|| {
+                patches.iter().map(|p| unsafe { discrepancy_popcnt(black_box(p), black_box(&model)) } as u32).sum::<u32>()
+            }));
+        }
+    }
+    group.bench_function("naive", |b| b.iter(|| {
+        patches.iter().map(|p| discrepancy_naive(black_box(p), black_box(&model)) as u32).sum::<u32>()
+    }));
+    group.finish();
+}
+
+criterion_group!(benches, bench_discrepancy);
+criterion_main!(benches);
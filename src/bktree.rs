@@ -0,0 +1,148 @@
+//! A BK-tree over descriptors, for finding all descriptors within
+//! a given Hamming distance of a query without comparing against
+//! every stored descriptor.
+
+use imagematch::{hamming,PatchDescriptor};
+
+/// A metric tree over descriptors using Hamming distance. Each child
+/// of a node is keyed by its distance from that node, so by the triangle
+/// inequality a query only needs to visit children whose key is within
+/// the search radius of the query's distance from the node.
+#[derive(Clone, Debug, Default)]
+pub struct BkTree {
+    root: Option<Node>
+}
+
+#[derive(Clone, Debug)]
+struct Node {
+    descriptor: PatchDescriptor,
+    children: Vec<(u16, Node)>
+}
+
+impl BkTree {
+
+    /// Create an empty tree.
+    pub fn new() -> BkTree {
+        BkTree { root: None }
+    }
+
+    /// Add a descriptor to the tree.
+    pub fn insert(&mut self, descriptor: PatchDescriptor) {
+        let mut node = match self.root {
+            None => {
+                self.root = Some(Node { descriptor, children: Vec::new() });
+                return;
+            },
+            Some(ref mut root) => root
+        };
+
+        loop {
+            let distance = hamming(&node.descriptor, &descriptor);
+            let position = node.children.iter().position(|&(d, _)| d == distance);
+            match position {
+                Some(i) => node = &mut node.children[i].1,
+                None => {
+                    node.children.push((distance, Node { descriptor, children: Vec::new() }));
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Returns every descriptor within max_dist Hamming distance of d,
+    /// along with its distance from d.
+    pub fn query_within(&self, d: &PatchDescriptor, max_dist: u16) -> Vec<(&PatchDescriptor, u16)> {
+        let mut results = Vec::new();
+        let mut stack = Vec::new();
+        if let Some(ref root) = self.root {
+            stack.push(root);
+        }
+
+        while let Some(node) = stack.pop() {
+            let distance = hamming(&node.descriptor, d);
+            if distance <= max_dist {
+                results.push((&node.descriptor, distance));
+            }
+            // Computed in u32 so that a large max_dist can't overflow
+            let (distance, max_dist) = (distance as u32, max_dist as u32);
+            for &(key, ref child) in &node.children {
+                let key = key as u32;
+                if key + max_dist >= distance && key <= distance + max_dist {
+                    stack.push(child);
+                }
+            }
+        }
+
+        results
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use rand::{Rng,SeedableRng};
+    use rand::rngs::StdRng;
+    use imagematch::{hamming,PatchDescriptor};
+    use super::BkTree;
+
+    /// Descriptors clustered around a few random centres.
+    fn clustered_descriptors(rng: &mut StdRng, count: usize) -> Vec<PatchDescriptor> {
+        let centres = (0..5).map(|_| rng.gen::<PatchDescriptor>()).collect::<Vec<_>>();
+        (0..count).map(|_| {
+            let mut d = centres[rng.gen_range(0..centres.len())];
+            for _ in 0..rng.gen_range(0..20) {
+                let bit = rng.gen_range(0..320);
+                d[bit / 64] ^= 1 << (bit % 64);
+            }
+            d
+        }).collect()
+    }
+
+    #[test]
+    fn test_query_within_matches_brute_force() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let descriptors = clustered_descriptors(&mut rng, 300);
+        let mut tree = BkTree::new();
+        for d in &descriptors {
+            tree.insert(*d);
+        }
+
+        for query in descriptors.iter().take(10) {
+            for &radius in &[0, 3, 10, 25, 160, 320] {
+                let mut expected = descriptors.iter()
+                    .map(|d| (*d, hamming(d, query)))
+                    .filter(|&(_, dist)| dist <= radius)
+                    .collect::<Vec<_>>();
+                let mut found = tree.query_within(query, radius).into_iter()
+                    .map(|(d, dist)| (*d, dist))
+                    .collect::<Vec<_>>();
+                expected.sort();
+                found.sort();
+                assert_eq!(found, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_query_within_max_radius() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let descriptors = clustered_descriptors(&mut rng, 100);
+        let mut tree = BkTree::new();
+        for d in &descriptors {
+            tree.insert(*d);
+        }
+
+        let mut found = tree.query_within(&descriptors[0], u16::MAX).into_iter()
+            .map(|(d, _)| *d)
+            .collect::<Vec<_>>();
+        let mut expected = descriptors.clone();
+        found.sort();
+        expected.sort();
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn test_query_empty_tree() {
+        assert!(BkTree::new().query_within(&[0; 5], 320).is_empty());
+    }
+}
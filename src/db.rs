@@ -0,0 +1,374 @@
+//! A collection of labelled descriptors which can be queried
+//! for the closest match to an observed patch.
+
+use std::io::{ErrorKind,Read,Write};
+use error::VisionError;
+use imagematch::{descriptor_from_bytes,descriptor_to_bytes,discrepancy,hamming,PatchDescriptor};
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+
+/// Identifies a file written by DescriptorDb::save.
+const MAGIC: &[u8; 4] = b"VSDB";
+
+/// The version of the format written by DescriptorDb::save.
+const FORMAT_VERSION: u32 = 1;
+
+/// A list of labelled model descriptors, e.g. one per
+/// template or object to be recognised.
+#[derive(Clone, Debug, Default)]
+pub struct DescriptorDb {
+    entries: Vec<(String, PatchDescriptor)>
+}
+
+impl DescriptorDb {
+
+    /// Create an empty database.
+    pub fn new() -> DescriptorDb {
+        DescriptorDb { entries: Vec::new() }
+    }
+
+    /// Add a labelled model descriptor to the database.
+    pub fn add<S: Into<String>>(&mut self, label: S, descriptor: PatchDescriptor) {
+        self.entries.push((label.into(), descriptor));
+    }
+
+    /// Writes the database in a binary format which can be read by load.
+    /// The format is the magic bytes "VSDB", a u32 version and a u64
+    /// entry count, followed by each entry as a u32 label length, the
+    /// UTF-8 label and the 40 bytes of its descriptor as written by
+    /// descriptor_to_bytes. All integers are little-endian.
+    pub fn save<W: Write>(&self, mut w: W) -> Result<(), VisionError> {
+        w.write_all(MAGIC)?;
+        w.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        w.write_all(&(self.entries.len() as u64).to_le_bytes())?;
+        for (label, descriptor) in &self.entries {
+            w.write_all(&(label.len() as u32).to_le_bytes())?;
+            w.write_all(label.as_bytes())?;
+            w.write_all(&descriptor_to_bytes(descriptor))?;
+        }
+        Ok(())
+    }
+
+    /// Reads a database written by save. Returns an error if the data
+    /// does not start with the expected magic bytes and version, or is
+    /// truncated or otherwise malformed.
+    pub fn load<R: Read>(mut r: R) -> Result<DescriptorDb, VisionError> {
+        let mut magic = [0u8; 4];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(VisionError::InvalidFormat("not a descriptor database".to_string()));
+        }
+        let mut version = [0u8; 4];
+        r.read_exact(&mut version)?;
+        let version = u32::from_le_bytes(version);
+        if version != FORMAT_VERSION {
+            return Err(VisionError::InvalidFormat(format!("unsupported version {}", version)));
+        }
+        let mut count = [0u8; 8];
+        r.read_exact(&mut count)?;
+        let count = u64::from_le_bytes(count);
+
+        let mut db = DescriptorDb::new();
+        for _ in 0..count {
+            let mut len = [0u8; 4];
+            r.read_exact(&mut len)?;
+            let len = u32::from_le_bytes(len) as usize;
+            // Read via take rather than allocating len bytes up
+            // front, as a corrupt length could be huge
+            let mut label = Vec::new();
+            (&mut r).take(len as u64).read_to_end(&mut label)?;
+            if label.len() != len {
+                return Err(VisionError::Io(ErrorKind::UnexpectedEof));
+            }
+            let label = String::from_utf8(label)
+                .map_err(|_| VisionError::InvalidFormat("label is not valid UTF-8".to_string()))?;
+            let mut descriptor = [0u8; 40];
+            r.read_exact(&mut descriptor)?;
+            db.add(label, descriptor_from_bytes(&descriptor));
+        }
+        Ok(db)
+    }
+
+    /// Returns the number of models in the database.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns true if the database contains no models.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Removes every model within max_dist Hamming distance of a model
+    /// added before it which has been kept, e.g. to discard near
+    /// duplicates trained from overlapping keypoints.
+    pub fn dedupe(&mut self, max_dist: u16) {
+        let mut kept: Vec<(String, PatchDescriptor)> = Vec::new();
+        for (label, descriptor) in self.entries.drain(..) {
+            if kept.iter().all(|(_, k)| hamming(k, &descriptor) > max_dist) {
+                kept.push((label, descriptor));
+            }
+        }
+        self.entries = kept;
+    }
+
+    /// Returns the label of the model with the lowest discrepancy
+    /// against the given patch, and that discrepancy, or None if the
+    /// database is empty. Ties are resolved in favour of the model
+    /// added first.
+    pub fn query(&self, patch: &PatchDescriptor) -> Option<(&str, u16)> {
+        let mut best: Option<(&str, u16)> = None;
+        for (label, model) in &self.entries {
+            let score = discrepancy(patch, model);
+            match best {
+                Some((_, s)) if s <= score => {},
+                _ => best = Some((label, score))
+            }
+        }
+        best
+    }
+
+    /// As query, but comparing the patch against the models in
+    /// parallel. Ties are still resolved in favour of the model
+    /// added first.
+    #[cfg(feature = "rayon")]
+    pub fn query_parallel(&self, patch: &PatchDescriptor) -> Option<(&str, u16)> {
+        self.entries.par_iter()
+            .enumerate()
+            .map(|(i, (label, model))| (discrepancy(patch, model), i, label.as_str()))
+            .min_by_key(|&(score, i, _)| (score, i))
+            .map(|(score, _, label)| (label, score))
+    }
+
+    /// Returns the labels and discrepancies of the (up to) k models
+    /// with the lowest discrepancy against the given patch, in
+    /// ascending order of discrepancy. Models with equal discrepancy
+    /// are returned in the order they were added.
+    pub fn query_k(&self, patch: &PatchDescriptor, k: usize) -> Vec<(&str, u16)> {
+        let mut scored = self.entries.iter()
+            .map(|(label, model)| (label.as_str(), discrepancy(patch, model)))
+            .collect::<Vec<_>>();
+        // sort_by_key is stable, so preserves insertion order for ties.
+        scored.sort_by_key(|&(_, score)| score);
+        scored.truncate(k);
+        scored
+    }
+
+    /// Returns the label of the best matching model only if its
+    /// discrepancy is less than ratio times that of the second best
+    /// model, i.e. if the best match is unambiguous. If the database
+    /// contains a single model then its label is always returned.
+    pub fn ratio_test(&self, patch: &PatchDescriptor, ratio: f32) -> Option<&str> {
+        let best = self.query_k(patch, 2);
+        match best.len() {
+            0 => None,
+            1 => Some(best[0].0),
+            _ => {
+                if (best[0].1 as f32) < ratio * best[1].1 as f32 {
+                    Some(best[0].0)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+/// Returns (i, j, distance) for each pair of entries where entry j of b
+/// is the nearest to entry i of a and entry i of a is the nearest to
+/// entry j of b, in ascending order of i. Distances are Hamming
+/// distances, and ties are resolved in favour of the entry added
+/// first. Keeping only such mutually best pairs discards many of the
+/// false matches a one-way query would return.
+pub fn mutual_best(a: &DescriptorDb, b: &DescriptorDb) -> Vec<(usize, usize, u16)> {
+    let backward = b.entries.iter()
+        .map(|(_, d)| nearest(d, a).map(|(i, _)| i))
+        .collect::<Vec<_>>();
+
+    a.entries.iter().enumerate()
+        .filter_map(|(i, (_, d))| nearest(d, b).map(|(j, dist)| (i, j, dist)))
+        .filter(|&(i, j, _)| backward[j] == Some(i))
+        .collect()
+}
+
+/// Returns the index and Hamming distance of the entry in db nearest
+/// to the given descriptor, resolving ties in favour of the first.
+fn nearest(descriptor: &PatchDescriptor, db: &DescriptorDb) -> Option<(usize, u16)> {
+    let mut best: Option<(usize, u16)> = None;
+    for (i, (_, other)) in db.entries.iter().enumerate() {
+        let dist = hamming(descriptor, other);
+        match best {
+            Some((_, d)) if d <= dist => {},
+            _ => best = Some((i, dist))
+        }
+    }
+    best
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::io::ErrorKind;
+    use error::VisionError;
+    use super::{
+        mutual_best,
+        DescriptorDb
+    };
+
+    #[test]
+    fn test_query() {
+        let mut db = DescriptorDb::new();
+        db.add("first", [!0, 0, 0, 0, 0]);
+        db.add("second", [0, !0, 0, 0, 0]);
+        db.add("third", [0, 0, !0, 0, 0]);
+
+        // A patch lying in bin 1 at half the locations and bin 0 elsewhere
+        // only lies in rare bins of the third model at locations in bin 2.
+        let patch = [0xffffffff, 0xffffffff00000000, 0, 0, 0];
+        assert_eq!(db.query(&patch), Some(("third", 0)));
+    }
+
+    #[test]
+    fn test_query_ties_resolve_to_earliest() {
+        let mut db = DescriptorDb::new();
+        db.add("first", [1, 0, 0, 0, 0]);
+        db.add("second", [0, 1, 0, 0, 0]);
+        assert_eq!(db.query(&[0; 5]), Some(("first", 0)));
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_query_parallel() {
+        let mut db = DescriptorDb::new();
+        for i in 0..1000u64 {
+            let word = i.wrapping_mul(0x9e3779b97f4a7c15);
+            db.add(format!("model {}", i), [word, word.rotate_left(13), !word, word >> 7, word << 3]);
+        }
+        for i in 0..50u64 {
+            let word = i.wrapping_mul(0xbf58476d1ce4e5b9);
+            let patch = [word, !word, word.rotate_right(5), 0, word];
+            assert_eq!(db.query_parallel(&patch), db.query(&patch));
+        }
+        // An empty patch has zero discrepancy against every model
+        let zeros = [0; 5];
+        assert_eq!(db.query_parallel(&zeros), Some(("model 0", 0)));
+        assert_eq!(db.query_parallel(&zeros), db.query(&zeros));
+        assert_eq!(DescriptorDb::new().query_parallel(&zeros), None);
+    }
+
+    #[test]
+    fn test_mutual_best() {
+        let mut a = DescriptorDb::new();
+        a.add("a0", [0b1111, 0, 0, 0, 0]);
+        a.add("a1", [0b1110, 0, 0, 0, 0]);
+        a.add("a2", [0, 0, 0, 0, !0]);
+
+        let mut b = DescriptorDb::new();
+        b.add("b0", [0, !0, 0, 0, 0]);
+        b.add("b1", [0b1100, 0, 0, 0, 0]);
+        b.add("b2", [0, 0, 0, 0, !0 >> 8]);
+
+        // a0 and a1 are both nearest to b1, which is nearest to a1. b0
+        // is nearest to a1 too, but a1 prefers b1. a2 and b2 agree.
+        assert_eq!(mutual_best(&a, &b), vec![(1, 1, 1), (2, 2, 8)]);
+        assert_eq!(mutual_best(&b, &a), vec![(1, 1, 1), (2, 2, 8)]);
+        assert!(mutual_best(&a, &DescriptorDb::new()).is_empty());
+    }
+
+    #[test]
+    fn test_query_empty() {
+        assert_eq!(DescriptorDb::new().query(&[0; 5]), None);
+    }
+
+    #[test]
+    fn test_dedupe() {
+        let mut db = DescriptorDb::new();
+        db.add("a", [0b1111, 0, 0, 0, 0]);
+        db.add("b", [0; 5]);
+        db.add("c", [0b1100, 0, 0, 0, 0]);
+        assert_eq!(db.len(), 3);
+
+        db.dedupe(2);
+        assert_eq!(db.len(), 2);
+        assert_eq!(db.query_k(&[!0; 5], 3), vec![("b", 0), ("a", 4)]);
+
+        db.dedupe(4);
+        assert_eq!(db.len(), 1);
+        assert_eq!(db.query(&[0; 5]), Some(("a", 0)));
+    }
+
+    #[test]
+    fn test_save_load_round_trip() {
+        let mut db = DescriptorDb::new();
+        db.add("first", [1, 2, 3, 4, 5]);
+        db.add("", [0; 5]);
+        db.add("ünïcödé", [!0, 0, 1 << 63, 7, 0xdead_beef]);
+
+        let mut bytes = Vec::new();
+        db.save(&mut bytes).unwrap();
+        assert_eq!(&bytes[..4], b"VSDB");
+
+        let loaded = DescriptorDb::load(&bytes[..]).unwrap();
+        assert_eq!(loaded.entries, db.entries);
+        assert!(DescriptorDb::load(&b"VSDB\x01\0\0\0\0\0\0\0\0\0\0\0"[..]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_load_rejects_bad_data() {
+        let mut db = DescriptorDb::new();
+        db.add("label", [1, 2, 3, 4, 5]);
+        let mut bytes = Vec::new();
+        db.save(&mut bytes).unwrap();
+
+        let mut bad_magic = bytes.clone();
+        bad_magic[0] = b'X';
+        assert_eq!(DescriptorDb::load(&bad_magic[..]).unwrap_err(),
+            VisionError::InvalidFormat("not a descriptor database".to_string()));
+
+        let mut bad_version = bytes.clone();
+        bad_version[4] = 9;
+        assert_eq!(DescriptorDb::load(&bad_version[..]).unwrap_err(),
+            VisionError::InvalidFormat("unsupported version 9".to_string()));
+
+        let truncated = &bytes[..bytes.len() - 1];
+        assert_eq!(DescriptorDb::load(truncated).unwrap_err(), VisionError::Io(ErrorKind::UnexpectedEof));
+        assert_eq!(DescriptorDb::load(&bytes[..2]).unwrap_err(), VisionError::Io(ErrorKind::UnexpectedEof));
+    }
+
+    #[test]
+    fn test_query_k() {
+        let mut db = DescriptorDb::new();
+        db.add("a", [0xff, 0, 0, 0, 0]);
+        db.add("b", [0xf, 0, 0, 0, 0]);
+        db.add("c", [0x3, 0, 0, 0, 0]);
+        db.add("d", [0xf0, 0, 0, 0, 0]);
+
+        let patch = [!0, 0, 0, 0, 0];
+        assert_eq!(db.query_k(&patch, 2), vec![("c", 2), ("b", 4)]);
+        assert_eq!(db.query_k(&patch, 10), vec![("c", 2), ("b", 4), ("d", 4), ("a", 8)]);
+        assert_eq!(db.query_k(&patch, 0), vec![]);
+    }
+
+    #[test]
+    fn test_ratio_test() {
+        let mut db = DescriptorDb::new();
+        db.add("a", [0xff, 0, 0, 0, 0]);
+        db.add("b", [0x3, 0, 0, 0, 0]);
+
+        let patch = [!0, 0, 0, 0, 0];
+        // Discrepancies are 2 for b and 8 for a.
+        assert_eq!(db.ratio_test(&patch, 0.5), Some("b"));
+        assert_eq!(db.ratio_test(&patch, 0.25), None);
+
+        db.add("c", [0x3 << 8, 0, 0, 0, 0]);
+        assert_eq!(db.ratio_test(&patch, 0.8), None);
+    }
+
+    #[test]
+    fn test_ratio_test_small_databases() {
+        let mut db = DescriptorDb::new();
+        assert_eq!(db.ratio_test(&[0; 5], 0.8), None);
+        db.add("only", [1, 0, 0, 0, 0]);
+        assert_eq!(db.ratio_test(&[0; 5], 0.8), Some("only"));
+    }
+}
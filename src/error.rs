@@ -0,0 +1,78 @@
+//! The error type for fallible operations in this crate.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// The ways in which training or matching can fail.
+#[derive(Debug, Clone, PartialEq)]
+pub enum VisionError {
+    /// The image is too small for a patch to be sampled
+    /// at any location.
+    ImageTooSmall,
+    /// The patch has (close to) zero variance, so cannot
+    /// be normalised.
+    FlatPatch,
+    /// A rarity threshold lay outside 0.0..=1.0.
+    InvalidThreshold(f32),
+    /// No keypoints were provided to train from.
+    NoKeypoints,
+    /// A sample had the wrong number of pixels.
+    WrongLength { expected: usize, actual: usize },
+    /// A model had a different number of bins from the
+    /// configuration it was used with.
+    BinCountMismatch { expected: usize, actual: usize },
+    /// Reading or writing failed.
+    Io(io::ErrorKind),
+    /// Data being loaded was not in the expected format.
+    InvalidFormat(String)
+}
+
+impl fmt::Display for VisionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            VisionError::ImageTooSmall =>
+                write!(f, "image is too small to sample a patch"),
+            VisionError::FlatPatch =>
+                write!(f, "patch has zero variance and cannot be normalised"),
+            VisionError::InvalidThreshold(t) =>
+                write!(f, "threshold {} is not in the range 0.0..=1.0", t),
+            VisionError::NoKeypoints =>
+                write!(f, "no keypoints were provided"),
+            VisionError::WrongLength { expected, actual } =>
+                write!(f, "expected {} pixels but found {}", expected, actual),
+            VisionError::BinCountMismatch { expected, actual } =>
+                write!(f, "expected a model with {} bins but found {}", expected, actual),
+            VisionError::Io(kind) =>
+                write!(f, "I/O error: {}", kind),
+            VisionError::InvalidFormat(ref reason) =>
+                write!(f, "invalid format: {}", reason)
+        }
+    }
+}
+
+impl Error for VisionError {}
+
+impl From<io::Error> for VisionError {
+    fn from(e: io::Error) -> VisionError {
+        VisionError::Io(e.kind())
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::VisionError;
+
+    #[test]
+    fn test_display() {
+        assert_eq!(VisionError::InvalidThreshold(1.5).to_string(),
+            "threshold 1.5 is not in the range 0.0..=1.0");
+        assert_eq!(VisionError::ImageTooSmall.to_string(),
+            "image is too small to sample a patch");
+        assert_eq!(VisionError::WrongLength { expected: 64, actual: 10 }.to_string(),
+            "expected 64 pixels but found 10");
+        assert_eq!(VisionError::InvalidFormat("unsupported version 9".to_string()).to_string(),
+            "invalid format: unsupported version 9");
+    }
+}
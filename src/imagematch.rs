@@ -1,16 +1,36 @@
 //! An implementation of the algorithm described in http://www.edwardrosten.com/work/taylor_2009_robust.pdf.
 
-use image::{GenericImage,Luma,Pixel};
-use stats::{mean,stddev};
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+use std::fmt;
+use std::ops::{Add,AddAssign,Index,IndexMut};
+use std::sync::OnceLock;
+use std::sync::mpsc::Receiver;
+use image::{GenericImageView,ImageBuffer,Luma,Primitive,Rgb};
+use image::imageops::{resize,FilterType};
+use error::VisionError;
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+#[cfg(feature = "serde")]
+use serde::{Deserialize,Serialize};
 
 /// Counts of samples seen at a given location which
-/// fall into each intensity range.
-pub type PointHist = [u32; 5];
+/// fall into each of B intensity ranges.
+pub type PointHist<const B: usize = 5> = [u32; B];
 
 /// A point histogram of observed pixel intensities at
-/// each location in an 8x8 template.
-struct PatchModel {
-    hists: [PointHist; 64]
+/// each of N locations in a template, with B intensity bins
+/// per location. N defaults to the 64 locations of an 8x8
+/// template and B to 5 bins.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PatchModel<const N: usize = 64, const B: usize = 5> {
+    #[cfg_attr(feature = "serde", serde(with = "serde_hists"))]
+    hists: [PointHist<B>; N],
+    /// The result of quantise, computed on the first call to
+    /// descriptor and discarded whenever the histograms change.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    cached: OnceLock<PatchDescriptor>
 }
 
 /// The result of quantising the 5-bin histograms
@@ -19,120 +39,3894 @@ struct PatchModel {
 /// in each of the 64 location bins.
 pub type PatchDescriptor = [u64; 5];
 
+/// Common reductions over the bins of a point histogram.
+pub trait PointHistExt {
+    /// The number of samples in the histogram, saturating at u32::MAX.
+    fn total(&self) -> u32;
+
+    /// The index of the bin holding the most samples. Ties, including
+    /// an empty histogram, are resolved in favour of the lowest bin.
+    fn mode_bin(&self) -> usize;
+
+    /// Returns true if the histogram holds no samples.
+    fn is_empty(&self) -> bool;
+}
+
+impl<const B: usize> PointHistExt for PointHist<B> {
+    fn total(&self) -> u32 {
+        hist_sum(self).min(u32::MAX as u64) as u32
+    }
+
+    fn mode_bin(&self) -> usize {
+        let mut mode = 0;
+        for i in 1..B {
+            if self[i] > self[mode] {
+                mode = i;
+            }
+        }
+        mode
+    }
+
+    fn is_empty(&self) -> bool {
+        self.iter().all(|&c| c == 0)
+    }
+}
+
+/// Decides whether a bin of a point histogram is rare, i.e. should
+/// be marked in the quantised descriptor.
+pub trait RarityRule {
+    /// Returns true if a bin holding bin_count of the total samples
+    /// at its location is rare. This is never called with a total of
+    /// zero, as every bin of an empty histogram is rare. The total is
+    /// a u64 as the sum of several u32 counts may not fit in a u32.
+    fn is_rare(&self, bin_count: u32, total: u64) -> bool;
+}
+
+/// Marks a bin as rare if it holds less than the wrapped fraction
+/// of the samples at its location. FractionRule(0.05) is the rule
+/// used by PatchModel::quantise.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FractionRule(pub f32);
+
+impl RarityRule for FractionRule {
+    fn is_rare(&self, bin_count: u32, total: u64) -> bool {
+        (bin_count as f32 / total as f32) < self.0
+    }
+}
+
+/// A PatchDescriptor wrapped in a struct so that it can
+/// implement traits such as serde's Serialize and Deserialize.
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Descriptor(pub PatchDescriptor);
+
+impl fmt::Debug for Descriptor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Descriptor([")?;
+        for (i, word) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{:#018x}", word)?;
+        }
+        write!(f, "])")
+    }
+}
+
+/// A discrepancy score, as returned by score. Lower is better:
+/// a discrepancy of 0 is a perfect match. Scores are ordered by
+/// value, so the best of several is the minimum.
+#[must_use]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Discrepancy(pub u16);
+
+impl fmt::Display for Discrepancy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+/// A similarity score, as returned by score_similarity. Higher is better:
+/// a similarity of 1.0 is a perfect match. Scores are ordered by
+/// value, so the best of several is the maximum. The order is total,
+/// using f32::total_cmp, so that scores can be sorted.
+#[must_use]
+#[derive(Clone, Copy, Debug)]
+pub struct Similarity(pub f32);
+
+impl PartialEq for Similarity {
+    fn eq(&self, other: &Similarity) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Similarity {}
+
+impl PartialOrd for Similarity {
+    fn partial_cmp(&self, other: &Similarity) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Similarity {
+    fn cmp(&self, other: &Similarity) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+impl fmt::Display for Similarity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+/// A descriptor in which only some locations take part in
+/// matching, e.g. to ignore an occluded part of a template.
+/// Bit h of active is set if location h participates.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MaskedDescriptor {
+    pub descriptor: PatchDescriptor,
+    pub active: u64
+}
+
+impl<const N: usize, const B: usize> PatchModel<N, B> {
+
+    /// The number of 64-bit words needed to hold one bit
+    /// for each of the N locations.
+    pub const WORDS_PER_BIN: usize = N.div_ceil(64);
+
+    /// Add a patch of already normalised pixel intensities
+    /// to the per-pixel-location histograms. The patch may be
+    /// any contiguous buffer of N values, such as an array, a
+    /// Vec or a subslice of a larger buffer.
+    ///
+    /// Panics if the patch does not have length N.
+    pub fn add_sample<S: AsRef<[u8]>>(&mut self, sample: S) {
+        self.add_sample_weighted(sample, 1);
+    }
+
+    /// As add_sample, but counting the sample weight times, e.g.
+    /// to emphasise samples with a high detector confidence.
+    pub fn add_sample_weighted<S: AsRef<[u8]>>(&mut self, sample: S, weight: u32) {
+        let sample: &[u8; N] = as_patch(sample.as_ref());
+        self.invalidate();
+        for (i, &value) in sample.iter().enumerate() {
+            let count = &mut self.hists[i][bin(value, B)];
+            *count = count.saturating_add(weight);
+        }
+    }
+
+    /// As add_sample, but returning an error rather than
+    /// panicking unless the slice has length N.
+    pub fn add_sample_slice(&mut self, sample: &[u8]) -> Result<(), VisionError> {
+        if sample.len() != N {
+            return Err(VisionError::WrongLength { expected: N, actual: sample.len() });
+        }
+        self.add_sample(sample);
+        Ok(())
+    }
+
+    /// Normalise a patch of raw pixel intensities and add it
+    /// to the per-pixel-location histograms. As with add_sample,
+    /// the patch may be any contiguous buffer of N values.
+    ///
+    /// Panics if the patch does not have length N.
+    pub fn add_normalised_sample<T: Primitive, S: AsRef<[T]>>(&mut self, raw: S) {
+        let raw: &[T; N] = as_patch(raw.as_ref());
+        self.add_sample(normalise(raw));
+    }
+
+    /// Subtract the mean from a patch of raw pixel intensities and
+    /// add it to the per-pixel-location histograms. Unlike
+    /// add_normalised_sample this is invariant only to brightness
+    /// offsets, not contrast, but is well-defined for flat patches.
+    pub fn add_sample_demeaned<T: Primitive>(&mut self, raw: &[T; N]) {
+        self.add_sample(demean(raw));
+    }
+
+    /// Add a patch of z-scores to the per-pixel-location histograms,
+    /// binning each with bin_f32.
+    pub fn add_sample_f32(&mut self, sample: &[f32; N]) {
+        self.invalidate();
+        for (i, &z) in sample.iter().enumerate() {
+            let count = &mut self.hists[i][bin_f32(z, B) as usize];
+            *count = count.saturating_add(1);
+        }
+    }
+
+    /// As add_normalised_sample, but binning the z-scores of the
+    /// raw intensities directly rather than first storing them as u8.
+    pub fn add_normalised_sample_f32<T: Primitive>(&mut self, raw: &[T; N]) {
+        self.add_sample_f32(&normalise_f32(raw));
+    }
+
+    /// As add_normalised_sample, but returning an error rather
+    /// than adding a mid-gray sample if the patch is flat.
+    pub fn try_add_normalised_sample<T: Primitive>(&mut self, raw: &[T; N]) -> Result<(), VisionError> {
+        if is_flat(mean_and_stddev(raw).1) {
+            return Err(VisionError::FlatPatch);
+        }
+        self.add_normalised_sample(raw);
+        Ok(())
+    }
+
+    /// Remove every recorded sample, so that the model can be
+    /// retrained without allocating a new one.
+    pub fn clear(&mut self) {
+        self.invalidate();
+        for hist in self.hists.iter_mut() {
+            *hist = [0; B];
+        }
+    }
+
+    /// Add the histogram counts of another model into this one,
+    /// e.g. to combine models trained on separate data. Counts
+    /// saturate at u32::MAX rather than overflowing.
+    pub fn merge(&mut self, other: &PatchModel<N, B>) {
+        self.invalidate();
+        for h in 0..N {
+            for i in 0..B {
+                self.hists[h][i] = self.hists[h][i].saturating_add(other.hists[h][i]);
+            }
+        }
+    }
+
+    /// Create a model whose histogram counts are the sums of
+    /// those in the given models.
+    pub fn merged(models: &[PatchModel<N, B>]) -> PatchModel<N, B> {
+        let mut result = PatchModel::default();
+        for model in models {
+            result.merge(model);
+        }
+        result
+    }
+
+    /// Returns an iterator over the histograms at each location,
+    /// in the order the locations are sampled by sample_patch.
+    pub fn histograms(&self) -> impl Iterator<Item = &PointHist<B>> {
+        self.hists.iter()
+    }
+
+    /// Returns the histogram at the given location, or None
+    /// if the index is out of range.
+    pub fn histogram(&self, index: usize) -> Option<&PointHist<B>> {
+        self.hists.get(index)
+    }
+
+    /// Returns the number of samples recorded at each location,
+    /// saturating at u32::MAX.
+    pub fn location_counts(&self) -> [u32; N] {
+        let mut counts = [0u32; N];
+        for (h, hist) in self.hists.iter().enumerate() {
+            counts[h] = hist.total();
+        }
+        counts
+    }
+
+    /// Returns true if any histogram count has reached u32::MAX, so
+    /// that further samples are no longer being fully recorded.
+    pub fn is_saturated(&self) -> bool {
+        self.hists.iter().any(|hist| hist.contains(&u32::MAX))
+    }
+
+    /// Returns the number of samples recorded at the least
+    /// trained location. Every location receives a value from
+    /// each sample, so this is normally the number of samples
+    /// added, but can be lower if histograms have been edited.
+    pub fn sample_count(&self) -> u32 {
+        self.location_counts().iter().cloned().min().unwrap_or(0)
+    }
+
+    /// Returns true if every location has recorded at
+    /// least min_samples samples.
+    pub fn is_trained(&self, min_samples: u32) -> bool {
+        self.sample_count() >= min_samples
+    }
+
+    /// Convert the N B-bin intensity histograms into B bitsets
+    /// of WORDS_PER_BIN 64-bit words each, laid out one after
+    /// another. Location h of bin j is bit h % 64 of word
+    /// j * WORDS_PER_BIN + h / 64, and is set to 1 if fewer than
+    /// 5% of the values in the hth histogram lie in bin j.
+    ///
+    /// A location which has never received a sample has
+    /// every bin marked as rare, so that it contributes the
+    /// maximum possible discrepancy when matching.
+    pub fn quantise_words(&self) -> Vec<u64> {
+        let mut words = vec![0u64; B * Self::WORDS_PER_BIN];
+        self.quantise_words_into(&FractionRule(0.05), 0, &mut words);
+        words
+    }
+
+    /// As quantise_words, but marking a bin as rare if the fraction
+    /// of values in it is less than cutoff rather than 5%. Returns
+    /// an error if cutoff is not in 0.0..=1.0.
+    pub fn quantise_words_with_threshold(&self, cutoff: f32) -> Result<Vec<u64>, VisionError> {
+        if !(0.0..=1.0).contains(&cutoff) {
+            return Err(VisionError::InvalidThreshold(cutoff));
+        }
+        let mut words = vec![0u64; B * Self::WORDS_PER_BIN];
+        self.quantise_words_into(&FractionRule(cutoff), 0, &mut words);
+        Ok(words)
+    }
+
+    /// Returns the index and both histograms of every location at
+    /// which this model and other differ, in order of location.
+    /// The result is empty if the models are identical.
+    pub fn diff(&self, other: &PatchModel<N, B>) -> Vec<(usize, PointHist<B>, PointHist<B>)> {
+        self.hists.iter().zip(other.hists.iter()).enumerate()
+            .filter(|&(_, (a, b))| a != b)
+            .map(|(h, (a, b))| (h, *a, *b))
+            .collect()
+    }
+
+    /// Returns the Shannon entropy in bits of the histogram at each
+    /// location, averaged over all locations. A model trained on
+    /// featureless patches has low entropy, so is unlikely to match
+    /// discriminatively. Locations with no samples have entropy 0.
+    pub fn mean_entropy(&self) -> f32 {
+        if N == 0 {
+            return 0.0;
+        }
+        let mut total = 0.0;
+        for hist in self.hists.iter() {
+            let sum = hist_sum(hist);
+            for &c in hist.iter().filter(|&&c| c > 0) {
+                let p = c as f64 / sum as f64;
+                total -= p * p.log2();
+            }
+        }
+        (total / N as f64) as f32
+    }
+
+    /// Returns the number of bins, across all locations, whose
+    /// fraction of samples lies within margin of cutoff. These bins
+    /// may change between rare and common under a small change to
+    /// the threshold, so a high count indicates a fragile descriptor.
+    /// Locations which have never received a sample are ignored.
+    pub fn descriptor_stability(&self, cutoff: f32, margin: f32) -> u16 {
+        let mut count = 0u16;
+        for hist in self.hists.iter() {
+            let sum = hist_sum(hist);
+            if sum == 0 {
+                continue;
+            }
+            for &c in hist.iter() {
+                let fraction = c as f32 / sum as f32;
+                if (fraction - cutoff).abs() <= margin {
+                    count = count.saturating_add(1);
+                }
+            }
+        }
+        count
+    }
+
+    /// Discards the cached descriptor. Must be called by every method
+    /// which changes the histograms.
+    fn invalidate(&mut self) {
+        self.cached.take();
+    }
+
+    /// Locations with fewer than min_samples samples are skipped,
+    /// leaving all of their bits unset.
+    fn quantise_words_into<R: RarityRule>(&self, rule: &R, min_samples: u32, words: &mut [u64]) {
+        let words_per_bin = Self::WORDS_PER_BIN;
+
+        for h in 0..N {
+            if self.hists[h].total() < min_samples {
+                continue;
+            }
+            let rare = rare_bins(&self.hists[h], rule);
+            for i in 0..B {
+                if rare[i] {
+                    set_bit_multi(&mut words[i * words_per_bin..(i + 1) * words_per_bin], h);
+                }
+            }
+        }
+    }
+}
+
+/// Views a slice as a patch of N values without copying it.
+///
+/// Panics if the slice does not have length N.
+fn as_patch<T, const N: usize>(sample: &[T]) -> &[T; N] {
+    match <&[T; N]>::try_from(sample) {
+        Ok(patch) => patch,
+        Err(_) => panic!("expected a patch of {} values, found {}", N, sample.len())
+    }
+}
+
+/// Returns the total count of a histogram. Unlike PointHistExt::total
+/// this is exact, as the sum of several u32 counts may not fit in a
+/// u32.
+fn hist_sum<const B: usize>(hist: &PointHist<B>) -> u64 {
+    hist.iter().fold(0, |x, &y| x + y as u64)
+}
+
+/// Returns whether each bin of a histogram is rare under the given
+/// rule. Every bin of an empty histogram is rare.
+fn rare_bins<R: RarityRule, const B: usize>(hist: &PointHist<B>, rule: &R) -> [bool; B] {
+    let mut rare = [true; B];
+    if hist.is_empty() {
+        return rare;
+    }
+    let total = hist_sum(hist);
+    for i in 0..B {
+        rare[i] = rule.is_rare(hist[i], total);
+    }
+    rare
+}
+
+/// Indexing a model returns the histogram at a location. As with
+/// slices, this panics if the location is out of range.
+impl<const N: usize, const B: usize> Index<usize> for PatchModel<N, B> {
+    type Output = PointHist<B>;
+
+    fn index(&self, location: usize) -> &PointHist<B> {
+        &self.hists[location]
+    }
+}
+
+impl<const N: usize, const B: usize> IndexMut<usize> for PatchModel<N, B> {
+    fn index_mut(&mut self, location: usize) -> &mut PointHist<B> {
+        self.invalidate();
+        &mut self.hists[location]
+    }
+}
+
+/// Adding two models merges their histogram counts, as in merge.
+impl<const N: usize, const B: usize> Add for PatchModel<N, B> {
+    type Output = PatchModel<N, B>;
+
+    fn add(mut self, other: PatchModel<N, B>) -> PatchModel<N, B> {
+        self.merge(&other);
+        self
+    }
+}
+
+impl<const N: usize, const B: usize> AddAssign for PatchModel<N, B> {
+    fn add_assign(&mut self, other: PatchModel<N, B>) {
+        self.merge(&other);
+    }
+}
+
+impl<const N: usize, const B: usize> Default for PatchModel<N, B> {
+    fn default() -> PatchModel<N, B> {
+        PatchModel { hists: [[0u32; B]; N], cached: OnceLock::new() }
+    }
+}
+
 impl PatchModel {
 
-    /// Add a patch of normalised pixel intensities to the
-    /// per-pixel-location histograms.
-    pub fn add_sample(&mut self, sample: &[u8; 64]) {
-        for i in 0..64 {
-            self.hists[i][bin(sample[i]) as usize] += 1;
+    /// Create a model of an 8x8 template with no samples
+    /// recorded at any location. Models of other sizes can
+    /// be created using Default, e.g. PatchModel::<256, 8>::default().
+    pub fn new() -> PatchModel {
+        PatchModel::default()
+    }
+
+    /// Create a model from patches of already normalised pixel
+    /// intensities, adding each as in add_sample.
+    pub fn from_samples<S: IntoIterator<Item=[u8; 64]>>(samples: S) -> PatchModel {
+        let mut model = PatchModel::new();
+        for sample in samples {
+            model.add_sample(sample);
+        }
+        model
+    }
+
+    /// Create a model from patches of raw pixel intensities,
+    /// normalising and adding each as in add_normalised_sample.
+    pub fn from_raw_samples<T, S>(samples: S) -> PatchModel
+        where T: Primitive, S: IntoIterator<Item=[T; 64]> {
+
+        let mut model = PatchModel::new();
+        for sample in samples {
+            model.add_normalised_sample(sample);
         }
+        model
     }
 
     /// Convert the 64 5-bin intensity histograms into
     /// 5 64-bit ints where the ith bit of the jth output int
     /// is set to 1 if fewer than 5% of the values in the ith
     /// histogram lie in bin j.
+    ///
+    /// A location which has never received a sample has
+    /// every bin marked as rare, so that it contributes the
+    /// maximum possible discrepancy when matching.
     pub fn quantise(&self) -> PatchDescriptor {
         let mut descriptor = [0u64; 5];
+        self.quantise_into(&mut descriptor);
+        descriptor
+    }
 
-        for h in 0..64 {
-            let hist = self.hists[h];
-            let sum = hist.iter().fold(0, |x, &y| x + y);
-            for i in 0..5 {
-                let fraction = hist[i] as f32 / sum as f32;
-                if fraction < 0.05 {
-                    descriptor[i] = set_bit(descriptor[i], h as u8);
-                }
+    /// Returns the same descriptor as quantise, but computes it only on
+    /// the first call after the model last changed, so is cheap to call
+    /// repeatedly once training has finished.
+    pub fn descriptor(&self) -> PatchDescriptor {
+        *self.cached.get_or_init(|| {
+            #[cfg(test)]
+            test::QUANTISE_CALLS.with(|calls| calls.set(calls.get() + 1));
+            self.quantise()
+        })
+    }
+
+    /// As quantise, but writing the descriptor into out, whose
+    /// previous contents are discarded.
+    pub fn quantise_into(&self, out: &mut PatchDescriptor) {
+        *out = [0u64; 5];
+        self.quantise_words_into(&FractionRule(0.05), 0, out);
+    }
+
+    /// As quantise, but using the given rule to decide which bins
+    /// are rare. Every bin of a location which has never received a
+    /// sample is rare, whatever the rule.
+    pub fn quantise_with_rule<R: RarityRule>(&self, rule: &R) -> PatchDescriptor {
+        let mut descriptor = [0u64; 5];
+        self.quantise_words_into(rule, 0, &mut descriptor);
+        descriptor
+    }
+
+    /// As quantise, but marking a bin as rare if the fraction of
+    /// values in it is less than cutoff rather than 5%. Returns
+    /// an error if cutoff is not in 0.0..=1.0.
+    pub fn quantise_with_threshold(&self, cutoff: f32) -> Result<PatchDescriptor, VisionError> {
+        if !(0.0..=1.0).contains(&cutoff) {
+            return Err(VisionError::InvalidThreshold(cutoff));
+        }
+        let mut descriptor = [0u64; 5];
+        self.quantise_words_into(&FractionRule(cutoff), 0, &mut descriptor);
+        Ok(descriptor)
+    }
+
+    /// As quantise_with_threshold, but leaving every bin unset at
+    /// locations with fewer than min_samples samples, as the fraction
+    /// of samples in each bin is too noisy to trust there. Such
+    /// locations never contribute to the discrepancy, so are ignored
+    /// when matching. Note that this includes locations which have
+    /// never received a sample whenever min_samples is at least 1,
+    /// unlike quantise, which marks every bin at those locations as
+    /// rare. With min_samples of 0 this is quantise_with_threshold.
+    pub fn quantise_with(&self, cutoff: f32, min_samples: u32) -> Result<PatchDescriptor, VisionError> {
+        if !(0.0..=1.0).contains(&cutoff) {
+            return Err(VisionError::InvalidThreshold(cutoff));
+        }
+        let mut descriptor = [0u64; 5];
+        self.quantise_words_into(&FractionRule(cutoff), min_samples, &mut descriptor);
+        Ok(descriptor)
+    }
+
+    /// Normalise and add each patch of raw pixel intensities received
+    /// from a channel, e.g. as sampled from a video feed by another
+    /// thread, until every sender has been dropped. Returns the number
+    /// of patches added.
+    pub fn train_stream(&mut self, rx: Receiver<[u8; 64]>) -> usize {
+        let mut count = 0;
+        for sample in rx {
+            self.add_normalised_sample(sample);
+            count += 1;
+        }
+        count
+    }
+
+    /// Returns a mask of the bins which quantise_with_threshold would
+    /// mark as rare at the given location, with bit i set if bin i
+    /// holds less than cutoff of the samples there, or None if the
+    /// location is out of range.
+    pub fn location_rarity(&self, location: usize, cutoff: f32) -> Option<u8> {
+        let hist = self.hists.get(location)?;
+        let rare = rare_bins(hist, &FractionRule(cutoff));
+        Some((0..5).filter(|&i| rare[i]).fold(0, |mask, i| mask | 1 << i))
+    }
+
+    /// Sample, normalise and add the patch centred at each keypoint.
+    /// Keypoints too near the image boundary to sample a patch are
+    /// skipped. Returns the number of samples added, or an error if
+    /// no keypoints are provided or the image is too small to sample
+    /// a patch at any location.
+    pub fn train_from_image<I>(&mut self, image: &I, keypoints: &[(u32, u32)]) -> Result<usize, VisionError>
+        where I: GenericImageView<Pixel=Luma<u8>> {
+
+        if keypoints.is_empty() {
+            return Err(VisionError::NoKeypoints);
+        }
+        check_size(image)?;
+        Ok(self.add_samples_at(image, keypoints))
+    }
+
+    /// As train_from_image, but splitting the keypoints into chunks
+    /// which are used to train separate models in parallel before
+    /// merging them into this one.
+    #[cfg(feature = "rayon")]
+    pub fn train_from_image_parallel<I>(&mut self, image: &I, keypoints: &[(u32, u32)]) -> Result<usize, VisionError>
+        where I: GenericImageView<Pixel=Luma<u8>> + Sync {
+
+        if keypoints.is_empty() {
+            return Err(VisionError::NoKeypoints);
+        }
+        check_size(image)?;
+
+        let (model, count) = keypoints.par_chunks(256)
+            .map(|chunk| {
+                let mut model = PatchModel::new();
+                let count = model.add_samples_at(image, chunk);
+                (model, count)
+            })
+            .reduce(|| (PatchModel::new(), 0), |(mut a, m), (b, n)| {
+                a.merge(&b);
+                (a, m + n)
+            });
+
+        self.merge(&model);
+        Ok(count)
+    }
+
+    /// Sample, normalise and add the patch centred at (x, y). Returns
+    /// false, leaving the model unchanged, if (x, y) is too near the
+    /// image boundary to sample a patch.
+    pub fn add_image_patch<I>(&mut self, image: &I, x: u32, y: u32) -> bool
+        where I: GenericImageView<Pixel=Luma<u8>> {
+
+        match sample_patch(image, x, y) {
+            Some(sample) => {
+                self.add_normalised_sample(sample);
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Sample, normalise and add the patch centred at (x, y) rotated
+    /// by each of the given angles in radians, as in
+    /// sample_patch_rotated, so that the model tolerates the object
+    /// appearing at any of those rotations. Returns the number of
+    /// rotations for which the patch lay within the image.
+    pub fn add_sample_rotations<I>(&mut self, image: &I, x: u32, y: u32, angles: &[f32]) -> usize
+        where I: GenericImageView<Pixel=Luma<u8>> {
+
+        let mut count = 0;
+        for &angle in angles {
+            if let Some(sample) = sample_patch_rotated(image, x as f32, y as f32, angle) {
+                self.add_normalised_sample(sample);
+                count += 1;
             }
         }
+        count
+    }
 
-        descriptor
+    fn add_samples_at<I>(&mut self, image: &I, keypoints: &[(u32, u32)]) -> usize
+        where I: GenericImageView<Pixel=Luma<u8>> {
+
+        keypoints.iter().filter(|&&(x, y)| self.add_image_patch(image, x, y)).count()
+    }
+}
+
+/// Serde doesn't support arrays of arbitrary length,
+/// so the histograms of a PatchModel are serialised
+/// as a sequence of sequences.
+#[cfg(feature = "serde")]
+mod serde_hists {
+    use serde::{Deserialize,Deserializer,Serializer};
+    use serde::de::Error;
+    use super::PointHist;
+
+    pub fn serialize<S, const N: usize, const B: usize>(hists: &[PointHist<B>; N], serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer {
+        serializer.collect_seq(hists.iter().map(|h| &h[..]))
+    }
+
+    pub fn deserialize<'de, D, const N: usize, const B: usize>(deserializer: D) -> Result<[PointHist<B>; N], D::Error>
+        where D: Deserializer<'de> {
+        let hists = Vec::<Vec<u32>>::deserialize(deserializer)?;
+        if hists.len() != N {
+            return Err(D::Error::invalid_length(hists.len(), &"one point histogram per location"));
+        }
+        let mut result = [[0u32; B]; N];
+        for (h, hist) in hists.iter().enumerate() {
+            if hist.len() != B {
+                return Err(D::Error::invalid_length(hist.len(), &"one count per bin"));
+            }
+            result[h].copy_from_slice(hist);
+        }
+        Ok(result)
     }
 }
 
+/// Sets bit pos of n. Positions beyond the end of a u64
+/// leave n unchanged.
 fn set_bit(n: u64, pos: u8) -> u64 {
-    n | (1 << pos)
+    n | 1u64.checked_shl(pos as u32).unwrap_or(0)
+}
+
+/// Sets bit pos of a multi-word bitset, in which bit pos is
+/// bit pos % 64 of word pos / 64. Positions beyond the end of
+/// the bitset leave it unchanged.
+fn set_bit_multi(words: &mut [u64], pos: usize) {
+    if let Some(word) = words.get_mut(pos / 64) {
+        *word = set_bit(*word, (pos % 64) as u8);
+    }
+}
+
+/// Returns the index of the intensity range containing value
+/// when the full range of an integer type, e.g. 0..=255 for u8,
+/// is split into the given number of bins, computed as
+/// floor(value * bins / range). The bin widths differ by at most
+/// one, e.g. 5 bins of u8 values are [0, 51], [52, 102], [103, 153],
+/// [154, 204] and [205, 255].
+///
+/// Earlier versions used bins of width ceil(range / bins), which
+/// left the last bin narrower than the others, so descriptors
+/// computed with this binning differ slightly from those.
+fn bin<T: Primitive>(value: T, bins: usize) -> usize {
+    let range = T::max_value().to_u64().unwrap() as u128 + 1;
+    (value.to_u64().unwrap() as u128 * bins as u128 / range) as usize
+}
+
+/// The magnitude beyond which z-scores are clamped by bin_f32.
+const MAX_Z: f32 = 3.0;
+
+/// Returns the bin containing a z-score, dividing the range
+/// -3.0..=3.0 into bins of equal width. Values outside this
+/// range are clamped into the first or last bin.
+///
+/// Panics if bins is zero.
+pub fn bin_f32(z: f32, bins: usize) -> u8 {
+    assert!(bins > 0, "cannot bin into zero bins");
+    let z = z.clamp(-MAX_Z, MAX_Z);
+    let b = ((z + MAX_Z) / (2.0 * MAX_Z) * bins as f32) as usize;
+    b.min(bins - 1) as u8
+}
+
+/// Quantise a single normalised patch into a descriptor
+/// where the ith bit of the jth output int is set to 1 if
+/// the pixel at location i lies in bin j.
+pub fn quantise_patch(sample: &[u8; 64]) -> PatchDescriptor {
+    let mut descriptor = [0u64; 5];
+    for (h, &value) in sample.iter().enumerate() {
+        let b = bin(value, 5);
+        descriptor[b] = set_bit(descriptor[b], h as u8);
+    }
+    descriptor
+}
+
+/// As quantise_patch, but using the given number of bins. Word j
+/// of the result holds the locations whose pixel lies in bin j,
+/// matching the layout of PatchModel::quantise_words for a model
+/// of 64 locations.
+pub fn quantise_patch_bins(sample: &[u8; 64], bins: usize) -> Vec<u64> {
+    let mut words = vec![0u64; bins];
+    for (h, &value) in sample.iter().enumerate() {
+        let b = bin(value, bins);
+        words[b] = set_bit(words[b], h as u8);
+    }
+    words
+}
+
+/// Quantises each of the given models.
+pub fn quantise_many(models: &[PatchModel]) -> Vec<PatchDescriptor> {
+    models.iter().map(|m| m.quantise()).collect()
 }
 
-fn bin(value: u8) -> u8 {
-    value / 52
+/// As quantise_many, but quantising the models in parallel.
+#[cfg(feature = "rayon")]
+pub fn quantise_many_parallel(models: &[PatchModel]) -> Vec<PatchDescriptor> {
+    models.par_iter().map(|m| m.quantise()).collect()
 }
 
 /// Returns the number of positions where the sampled pixel lies
 /// in a bin which has value 1 in the model, i.e. in a bin containing
 /// few training samples.
+///
+/// The five words are unrolled and summed pairwise so that the
+/// popcounts are independent rather than one serial chain, which
+/// matters when this is called once per pixel in a full-image scan.
+/// Each count_ones is a single popcnt instruction on targets which
+/// support it; benches/discrepancy.rs compares this with the
+/// alternatives.
+#[must_use]
 pub fn discrepancy(patch: &PatchDescriptor, model: &PatchDescriptor) -> u16 {
+    let a = (patch[0] & model[0]).count_ones() + (patch[1] & model[1]).count_ones();
+    let b = (patch[2] & model[2]).count_ones() + (patch[3] & model[3]).count_ones();
+    let c = (patch[4] & model[4]).count_ones();
+    (a + b + c) as u16
+}
+
+/// As discrepancy, but wrapped in a Discrepancy so that the type
+/// records that lower is better.
+///
+/// Dropping the result is a warning, and so fails to compile when
+/// `unused_must_use` is denied:
+///
+/// ```compile_fail
+/// #![deny(unused_must_use)]
+/// vision::score(&[0b1, 0, 0, 0, 0], &[0b11, 0, 0, 0, 0]);
+/// ```
+///
+/// ```
+/// #![deny(unused_must_use)]
+/// let d = vision::score(&[0b1, 0, 0, 0, 0], &[0b11, 0, 0, 0, 0]);
+/// assert_eq!(d, vision::Discrepancy(1));
+/// ```
+pub fn score(patch: &PatchDescriptor, model: &PatchDescriptor) -> Discrepancy {
+    Discrepancy(discrepancy(patch, model))
+}
+
+/// As discrepancy, but for descriptors with any number of words,
+/// such as those returned by quantise_words. Words beyond the end
+/// of the shorter descriptor are ignored.
+#[must_use]
+pub fn discrepancy_words(patch: &[u64], model: &[u64]) -> u16 {
+    patch.iter().zip(model.iter())
+        .map(|(p, m)| (p & m).count_ones() as u16)
+        .sum()
+}
+
+/// As discrepancy, but counting only the locations which are
+/// active in the model's mask.
+#[must_use]
+pub fn discrepancy_masked(patch: &PatchDescriptor, model: &MaskedDescriptor) -> u16 {
     let mut count = 0u16;
-    for i in 0..5 {
-        let intersect = patch[i] & model[i];
-        count += intersect.count_ones() as u16;
+    for (p, m) in patch.iter().zip(model.descriptor.iter()) {
+        count += (p & m & model.active).count_ones() as u16;
     }
     count
 }
 
-/// Normalise a range of values to have mean 0
-/// and variance 1.
-fn normalise(patch: &[u8; 64]) -> [u8; 64] {
-    let mean = mean(patch.iter().map(|x| *x));
-    let stddev = stddev(patch.iter().map(|x| *x));
-    let mut normalised = [0u8; 64];
-    for i in 0..64 {
-        let v = (patch[i] as f64 - mean) / stddev;
-        normalised[i] = v as u8;
+/// As discrepancy, but stopping as soon as the count exceeds max.
+/// Returns None if the discrepancy is greater than max, which is
+/// cheaper than computing it in full when most patches are rejected.
+#[must_use]
+pub fn discrepancy_bounded(patch: &PatchDescriptor, model: &PatchDescriptor, max: u16) -> Option<u16> {
+    let mut count = 0u16;
+    for (p, m) in patch.iter().zip(model.iter()) {
+        count += (p & m).count_ones() as u16;
+        if count > max {
+            return None;
+        }
     }
-    normalised
+    Some(count)
 }
 
-/// Samples an 8x8 patch of every-other-pixel around a given point.
-/// Return None if the pixel is too near an image boundary
-pub fn sample_patch<I>(image: &I, x: u32, y: u32) -> Option<[u8; 64]>
-    where I: GenericImage<Pixel=Luma<u8>> + 'static {
+/// As discrepancy, but weighting each location at which the patch
+/// lies in a rare bin of the model by min(1, count / min_samples),
+/// where count is the number of samples the model has seen at that
+/// location. This reduces the influence of locations whose rare bins
+/// are based on too few samples to be trusted.
+#[must_use]
+pub fn weighted_discrepancy(patch: &PatchDescriptor, model: &PatchModel, min_samples: u32) -> f32 {
+    let descriptor = model.quantise();
+    let counts = model.location_counts();
+    let mut total = 0f32;
 
-    let (width, height) = image.dimensions();
-    if x < 7 || y < 7 || x + 7 >= width || y + 7 >= height {
-        return None;
+    for (h, &count) in counts.iter().enumerate() {
+        let weight = if min_samples == 0 {
+            1.0
+        } else {
+            (count as f32 / min_samples as f32).min(1.0)
+        };
+        for i in 0..5 {
+            if (patch[i] & descriptor[i]) & (1 << h) != 0 {
+                total += weight;
+            }
+        }
     }
 
-    // +/- 1, 3, 5, 7
-    let offsets = (0..8).map(|x| 2 * x - 7).collect::<Vec<_>>();
-
-    let mut count = 0;
-    let mut sample = [0u8; 64];
+    total
+}
 
-    for dy in offsets.iter() {
-        for dx in offsets.iter() {
-            let p = image.get_pixel(x + dx, y + dy)[0];
-            sample[count] = p;
-            count += 1;
-        }
+/// Encodes a descriptor as its five words in order, each
+/// written in little-endian byte order regardless of the
+/// platform, so that the bytes are portable between machines.
+pub fn descriptor_to_bytes(d: &PatchDescriptor) -> [u8; 40] {
+    let mut bytes = [0u8; 40];
+    for (i, word) in d.iter().enumerate() {
+        bytes[8 * i..8 * i + 8].copy_from_slice(&word.to_le_bytes());
     }
+    bytes
+}
 
-    Some(sample)
+/// Decodes a descriptor written by descriptor_to_bytes.
+pub fn descriptor_from_bytes(b: &[u8; 40]) -> PatchDescriptor {
+    let mut d = [0u64; 5];
+    for i in 0..5 {
+        let mut word = [0u8; 8];
+        word.copy_from_slice(&b[8 * i..8 * i + 8]);
+        d[i] = u64::from_le_bytes(word);
+    }
+    d
 }
 
-#[cfg(test)]
-mod test {
+/// Renders a descriptor as a 40x8 image for debugging. The image is
+/// made of five 8x8 tiles side by side, one per intensity bin with
+/// the darkest bin leftmost. Within each tile, the pixel at (x, y)
+/// is 255 if location 8 * y + x is rare in that bin and 0 otherwise.
+pub fn descriptor_to_image(d: &PatchDescriptor) -> ImageBuffer<Luma<u8>, Vec<u8>> {
+    ImageBuffer::from_fn(40, 8, |x, y| {
+        let (bin, location) = (x / 8, 8 * y + x % 8);
+        if d[bin as usize] & (1 << location) != 0 { Luma([255]) } else { Luma([0]) }
+    })
+}
 
-    use super::{
-        bin,
-        count_bits,
-        set_bit
-    };
+/// Expands a descriptor into one float per bit, e.g. as a feature
+/// vector for a classifier. Element 64 * j + h is 1.0 if location h
+/// is rare in bin j, i.e. bit h of word j is set, and 0.0 otherwise.
+pub fn descriptor_to_features(d: &PatchDescriptor) -> [f32; 320] {
+    let mut features = [0f32; 320];
+    for (j, word) in d.iter().enumerate() {
+        for h in 0..64 {
+            if word & (1 << h) != 0 {
+                features[64 * j + h] = 1.0;
+            }
+        }
+    }
+    features
+}
+
+/// Returns the number of bits which differ between two descriptors.
+/// Unlike discrepancy, which counts only the bits set in both a patch
+/// and a model, this is symmetric in its arguments and is a true
+/// metric, so is suitable for comparing descriptors with each other.
+#[must_use]
+pub fn hamming(a: &PatchDescriptor, b: &PatchDescriptor) -> u16 {
+    let mut count = 0u16;
+    for (x, y) in a.iter().zip(b.iter()) {
+        count += (x ^ y).count_ones() as u16;
+    }
+    count
+}
+
+/// Combines descriptors of two views of the same object, marking
+/// a bin as rare only if it is rare in both. A patch resembling
+/// either view then has a low discrepancy, so use this to favour
+/// recall when the object's appearance varies between views.
+pub fn descriptor_and(a: &PatchDescriptor, b: &PatchDescriptor) -> PatchDescriptor {
+    let mut d = [0u64; 5];
+    for i in 0..5 {
+        d[i] = a[i] & b[i];
+    }
+    d
+}
+
+/// Combines descriptors of two views of the same object, marking
+/// a bin as rare if it is rare in either. A patch must then avoid
+/// the rare bins of every view to match, so use this to favour
+/// specificity and reject patches which only resemble one view.
+pub fn descriptor_or(a: &PatchDescriptor, b: &PatchDescriptor) -> PatchDescriptor {
+    let mut d = [0u64; 5];
+    for i in 0..5 {
+        d[i] = a[i] | b[i];
+    }
+    d
+}
+
+/// Returns the indices of the bins in which no location is rare, i.e.
+/// the words of a descriptor which are entirely zero. A model with such
+/// a bin is often degenerate, e.g. trained only on flat patches.
+pub fn empty_bins(d: &PatchDescriptor) -> Vec<usize> {
+    (0..5).filter(|&j| d[j] == 0).collect()
+}
+
+/// Returns the number of bits set in a descriptor.
+pub fn popcount(d: &PatchDescriptor) -> u16 {
+    d.iter().fold(0, |acc, w| acc + w.count_ones() as u16)
+}
+
+/// Returns the largest discrepancy which any patch can have against
+/// the model, i.e. the number of rare bins in the model, so that a
+/// threshold can be chosen as a fraction of it. Patches produced by
+/// quantise_patch set one bit per location, so can only reach this
+/// when the model has at most one rare bin at each location.
+#[must_use]
+pub fn max_discrepancy(model: &PatchDescriptor) -> u16 {
+    popcount(model)
+}
+
+/// Returns the fraction of the 320 bits of a descriptor which are
+/// set. A model descriptor with a low density has few rare bins, so
+/// matches almost any patch, which usually indicates a bland training
+/// patch or too little training data. A high density indicates a very
+/// specific model.
+pub fn rare_density(d: &PatchDescriptor) -> f32 {
+    popcount(d) as f32 / 320.0
+}
+
+/// Returns a score in 0.0..=1.0 where 1.0 indicates no discrepancy
+/// between patch and model. The discrepancy is scaled by the number
+/// of bits set in the model rather than by the 320 bits in a
+/// descriptor, as this is the largest discrepancy any patch can have
+/// against this model. A model with no bits set matches everything.
+/// The result is always finite, with 0.0 returned in place of any
+/// non-finite score.
+#[must_use]
+pub fn similarity(patch: &PatchDescriptor, model: &PatchDescriptor) -> f32 {
+    let max_possible = popcount(model);
+    if max_possible == 0 {
+        return 1.0;
+    }
+    finite_or(1.0 - discrepancy(patch, model) as f32 / max_possible as f32, 0.0)
+}
+
+/// As similarity, but wrapped in a Similarity so that the type
+/// records that higher is better.
+pub fn score_similarity(patch: &PatchDescriptor, model: &PatchDescriptor) -> Similarity {
+    Similarity(similarity(patch, model))
+}
+
+/// Returns the mean and population standard deviation
+/// of a range of values.
+fn mean_and_stddev<T: Primitive>(values: &[T]) -> (f64, f64) {
+    if values.is_empty() {
+        return (0.0, 0.0);
+    }
+    let n = values.len() as f64;
+    let mean = values.iter().fold(0.0, |acc, v| acc + v.to_f64().unwrap()) / n;
+    let variance = values.iter().fold(0.0, |acc, v| {
+        let d = v.to_f64().unwrap() - mean;
+        acc + d * d
+    }) / n;
+    (mean, variance.sqrt())
+}
+
+/// Patches with a standard deviation below this are
+/// treated as flat when normalising.
+const MIN_STDDEV: f64 = 1e-6;
+
+/// Returns true if a patch with the given standard deviation is
+/// flat. A patch of floats containing NaN or an infinity has a NaN
+/// standard deviation, and is also treated as flat so that these
+/// values are never propagated into z-scores or scores.
+fn is_flat(stddev: f64) -> bool {
+    stddev.is_nan() || stddev < MIN_STDDEV
+}
+
+/// Returns x if it is finite and sentinel otherwise.
+fn finite_or(x: f32, sentinel: f32) -> f32 {
+    if x.is_finite() { x } else { sentinel }
+}
+
+/// Normalise a range of values to have mean 0
+/// and variance 1. The resulting z-scores are stored
+/// as 128 + 32 * z, clamped to 0..=255, so that values
+/// within four standard deviations of the mean keep
+/// both their sign and magnitude.
+///
+/// A flat patch has no meaningful z-scores, so is
+/// mapped to mid-gray (128) at every location, as is
+/// any patch containing NaN or an infinity.
+pub(crate) fn normalise<T: Primitive, const N: usize>(patch: &[T; N]) -> [u8; N] {
+    let (mean, stddev) = mean_and_stddev(patch);
+    normalise_with(patch, mean, stddev)
+}
+
+/// As normalise, but using a precomputed mean and standard deviation.
+fn normalise_with<T: Primitive, const N: usize>(patch: &[T; N], mean: f64, stddev: f64) -> [u8; N] {
+    if is_flat(stddev) {
+        return [128u8; N];
+    }
+    let mut normalised = [0u8; N];
+    for i in 0..N {
+        let z = (patch[i].to_f64().unwrap() - mean) / stddev;
+        let v = 128.0 + 32.0 * z;
+        normalised[i] = v.clamp(0.0, 255.0) as u8;
+    }
+    normalised
+}
+
+/// As normalise, but returning the z-scores themselves rather than
+/// scaling and rounding them to u8. A flat patch is mapped to 0.0
+/// at every location, as is any patch containing NaN or an infinity,
+/// so the result is always finite.
+pub fn normalise_f32<T: Primitive, const N: usize>(patch: &[T; N]) -> [f32; N] {
+    let (mean, stddev) = mean_and_stddev(patch);
+    let mut normalised = [0f32; N];
+    if is_flat(stddev) {
+        return normalised;
+    }
+    for i in 0..N {
+        normalised[i] = ((patch[i].to_f64().unwrap() - mean) / stddev) as f32;
+    }
+    normalised
+}
+
+/// Subtract the mean from a range of values without dividing by
+/// the standard deviation. The centred values are rescaled so that
+/// the full range of T spans 256 levels and stored as 128 + value,
+/// clamped to 0..=255.
+fn demean<T: Primitive, const N: usize>(patch: &[T; N]) -> [u8; N] {
+    let (mean, _) = mean_and_stddev(patch);
+    let scale = 256.0 / (T::max_value().to_f64().unwrap() + 1.0);
+    let mut demeaned = [0u8; N];
+    for i in 0..N {
+        let v = 128.0 + scale * (patch[i].to_f64().unwrap() - mean);
+        demeaned[i] = v.round().clamp(0.0, 255.0) as u8;
+    }
+    demeaned
+}
+
+/// Offsets from the centre of the rows and columns sampled in a patch.
+const OFFSETS: [i32; 8] = [-7, -5, -3, -1, 1, 3, 5, 7];
+
+/// A source of pixel intensities which patches can be sampled from.
+/// This is implemented for every grayscale image from the image crate,
+/// and can be implemented for other pixel containers to sample from
+/// them without first copying into an ImageBuffer.
+pub trait PatchSource<T = u8> {
+    /// Returns the intensity at (x, y), or None if it lies
+    /// outside the source.
+    fn get_luma(&self, x: u32, y: u32) -> Option<T>;
+
+    /// Returns the width and height of the source.
+    fn dimensions(&self) -> (u32, u32);
+}
+
+impl<I, T> PatchSource<T> for I where I: GenericImageView<Pixel=Luma<T>>, T: Primitive {
+    fn get_luma(&self, x: u32, y: u32) -> Option<T> {
+        let (width, height) = GenericImageView::dimensions(self);
+        if x < width && y < height { Some(self.get_pixel(x, y)[0]) } else { None }
+    }
+
+    fn dimensions(&self) -> (u32, u32) {
+        GenericImageView::dimensions(self)
+    }
+}
+
+/// Samples an 8x8 patch of every-other-pixel around a given point.
+/// Return None if the pixel is too near an image boundary.
+/// Sampling does not allocate, so this is cheap to call once per
+/// pixel when scanning a full image.
+///
+/// The patch is in row-major order: element 8 * j + i is the pixel
+/// at (x - 7 + 2 * i, y - 7 + 2 * j). Every sampling function in this
+/// crate uses this order, as do descriptors and model locations.
+pub fn sample_patch<S, T>(source: &S, x: u32, y: u32) -> Option<[T; 64]>
+    where S: PatchSource<T>, T: Primitive {
+
+    sample_patch_aniso(source, x, y, 1, 1)
+}
+
+/// The layout of the 64 pixels of a sampled patch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SampleOrder {
+    /// Element 8 * j + i holds column i of row j, as in sample_patch.
+    RowMajor,
+    /// Element 8 * i + j holds column i of row j, e.g. for use with
+    /// descriptors trained by tools which sample columns first.
+    ColMajor
+}
+
+/// As sample_patch, but with the pixels laid out in the given order.
+pub fn sample_patch_order<S, T>(source: &S, x: u32, y: u32, order: SampleOrder) -> Option<[T; 64]>
+    where S: PatchSource<T>, T: Primitive {
+
+    let sample = sample_patch(source, x, y)?;
+    match order {
+        SampleOrder::RowMajor => Some(sample),
+        SampleOrder::ColMajor => {
+            let mut transposed = sample;
+            for j in 0..8 {
+                for i in 0..8 {
+                    transposed[8 * i + j] = sample[8 * j + i];
+                }
+            }
+            Some(transposed)
+        }
+    }
+}
+
+/// Returns the inclusive range (x_min, y_min, x_max, y_max) of centres
+/// at which sample_patch can sample an image of the given size, or None
+/// if the image is too small to sample a patch anywhere.
+pub fn valid_region(width: u32, height: u32) -> Option<(u32, u32, u32, u32)> {
+    if width < 15 || height < 15 {
+        return None;
+    }
+    Some((7, 7, width - 8, height - 8))
+}
+
+/// Returns the centres of a grid with the given spacing, starting
+/// at the top left of the valid region of an image of the given size,
+/// in row-major order. Every returned centre can be sampled, so these
+/// can be passed to train_from_image to train over a whole image.
+/// Returns no centres if the image is too small to sample a patch.
+///
+/// Panics if step is zero.
+pub fn grid_keypoints(width: u32, height: u32, step: u32) -> Vec<(u32, u32)> {
+    assert!(step > 0, "grid step must be positive");
+    let (x_min, y_min, x_max, y_max) = match valid_region(width, height) {
+        Some(region) => region,
+        None => return vec![]
+    };
+    let mut keypoints = Vec::new();
+    for y in (y_min..=y_max).step_by(step as usize) {
+        for x in (x_min..=x_max).step_by(step as usize) {
+            keypoints.push((x, y));
+        }
+    }
+    keypoints
+}
+
+/// Returns the valid region of an image, or an error if the
+/// image is too small to sample a patch anywhere.
+fn check_size<I: GenericImageView>(image: &I) -> Result<(u32, u32, u32, u32), VisionError> {
+    let (width, height) = image.dimensions();
+    valid_region(width, height).ok_or(VisionError::ImageTooSmall)
+}
+
+/// As sample_patch, but with the sampled pixels spaced 2 * stride
+/// apart, i.e. at offsets of +/- stride * (1, 3, 5, 7) from the centre.
+/// Return None if the pixel is within 7 * stride of an image boundary.
+pub fn sample_patch_strided<S, T>(source: &S, x: u32, y: u32, stride: u32) -> Option<[T; 64]>
+    where S: PatchSource<T>, T: Primitive {
+
+    sample_patch_aniso(source, x, y, stride, stride)
+}
+
+/// As sample_patch_strided, but with separate horizontal and vertical
+/// strides, e.g. to cover a target which is wider than it is tall. The
+/// sampled pixels lie at offsets of +/- stride_x * (1, 3, 5, 7) along
+/// x and +/- stride_y * (1, 3, 5, 7) along y. Return None if the pixel
+/// is within 7 * stride_x of the left or right boundary or within
+/// 7 * stride_y of the top or bottom boundary.
+pub fn sample_patch_aniso<S, T>(source: &S, x: u32, y: u32, stride_x: u32, stride_y: u32) -> Option<[T; 64]>
+    where S: PatchSource<T>, T: Primitive {
+
+    sample_grid(source.dimensions(), x, y, (stride_x, stride_y), |px, py| source.get_luma(px, py))
+}
+
+/// As sample_patch, but always returning a patch by reflecting
+/// coordinates which fall outside the image back into it. The
+/// reflection does not repeat the edge pixel, so the column at
+/// x = -1 is taken from x = 1 and the column at x = width is taken
+/// from x = width - 2. Coordinates are reflected as many times as
+/// needed, so any point inside a non-empty image may be used.
+///
+/// Panics if the image is empty.
+pub fn sample_patch_reflect<I>(image: &I, x: u32, y: u32) -> [u8; 64]
+    where I: GenericImageView<Pixel=Luma<u8>> {
+
+    sample_patch_reflect_strided(image, x, y, 1)
+}
+
+/// As sample_patch_reflect, but with the sampled pixels spaced
+/// 2 * stride apart, as in sample_patch_strided.
+///
+/// Panics if the image is empty.
+pub fn sample_patch_reflect_strided<I>(image: &I, x: u32, y: u32, stride: u32) -> [u8; 64]
+    where I: GenericImageView<Pixel=Luma<u8>> {
+
+    let (width, height) = image.dimensions();
+    assert!(width > 0 && height > 0, "cannot sample from an empty image");
+
+    let stride = stride as i64;
+    let mut count = 0;
+    let mut sample = [0u8; 64];
+
+    for &dy in OFFSETS.iter() {
+        for &dx in OFFSETS.iter() {
+            let px = reflect(x as i64 + stride * dx as i64, width);
+            let py = reflect(y as i64 + stride * dy as i64, height);
+            sample[count] = image.get_pixel(px, py)[0];
+            count += 1;
+        }
+    }
+
+    sample
+}
+
+/// Reflects a coordinate into 0..size without repeating the edge
+/// value, e.g. for size 4 the coordinates -2..6 map to 2 1 0 1 2 3 2 1.
+fn reflect(coord: i64, size: u32) -> u32 {
+    if size == 1 {
+        return 0;
+    }
+    let period = 2 * (size as i64 - 1);
+    let c = coord.rem_euclid(period);
+    if c < size as i64 { c as u32 } else { (period - c) as u32 }
+}
+
+/// As sample_patch, but for an RGB image. The luma of each sampled
+/// pixel is computed using the Rec. 601 weights
+/// 0.299 R + 0.587 G + 0.114 B.
+pub fn sample_patch_rgb<I>(image: &I, x: u32, y: u32) -> Option<[u8; 64]>
+    where I: GenericImageView<Pixel=Rgb<u8>> {
+
+    sample_grid(image.dimensions(), x, y, (1, 1), |px, py| {
+        let p = image.get_pixel(px, py);
+        let luma = 0.299 * p[0] as f32 + 0.587 * p[1] as f32 + 0.114 * p[2] as f32;
+        Some(luma.round().min(255.0) as u8)
+    })
+}
+
+/// As sample_patch, but for a centre at fractional coordinates.
+/// Each sampled value is bilinearly interpolated from the four
+/// surrounding pixels and rounded to the nearest integer. Returns
+/// None unless every pixel with a non-zero weight in the interpolation
+/// lies within the image, so that integer centres are accepted exactly
+/// where sample_patch accepts them.
+pub fn sample_patch_subpixel<I>(image: &I, x: f32, y: f32) -> Option<[u8; 64]>
+    where I: GenericImageView<Pixel=Luma<u8>> {
+
+    sample_patch_rotated(image, x, y, 0.0)
+}
+
+/// As sample_patch_subpixel, but with the sampling grid rotated
+/// clockwise about the centre by angle radians, so that the patch
+/// is sampled as if the image had been rotated anticlockwise.
+pub fn sample_patch_rotated<I>(image: &I, x: f32, y: f32, angle: f32) -> Option<[u8; 64]>
+    where I: GenericImageView<Pixel=Luma<u8>> {
+
+    let (width, height) = image.dimensions();
+    let (sin, cos) = angle.sin_cos();
+    let mut count = 0;
+    let mut sample = [0u8; 64];
+
+    for &dy in OFFSETS.iter() {
+        for &dx in OFFSETS.iter() {
+            let (dx, dy) = (dx as f32, dy as f32);
+            let (px, py) = (x + cos * dx - sin * dy, y + sin * dx + cos * dy);
+            if !(px >= 0.0 && py >= 0.0 && px + 1.0 <= width as f32 && py + 1.0 <= height as f32) {
+                return None;
+            }
+            let (left, top) = (px.floor(), py.floor());
+            let (fx, fy) = (px - left, py - top);
+            let (left, top) = (left as u32, top as u32);
+            // On the last row or column the neighbour has zero weight
+            let (right, bottom) = ((left + 1).min(width - 1), (top + 1).min(height - 1));
+
+            let p = |u, v| image.get_pixel(u, v)[0] as f32;
+            let upper = (1.0 - fx) * p(left, top) + fx * p(right, top);
+            let lower = (1.0 - fx) * p(left, bottom) + fx * p(right, bottom);
+            let value = (1.0 - fy) * upper + fy * lower;
+
+            sample[count] = value.round().min(255.0) as u8;
+            count += 1;
+        }
+    }
+
+    Some(sample)
+}
+
+/// Samples an 8x8 grid of pixels spaced 2 * stride_x apart
+/// horizontally and 2 * stride_y apart vertically around a given
+/// point in an image of the given dimensions, reading the intensity
+/// at each sampled location using get.
+fn sample_grid<T, F>((width, height): (u32, u32), x: u32, y: u32, (stride_x, stride_y): (u32, u32), get: F) -> Option<[T; 64]>
+    where T: Primitive, F: Fn(u32, u32) -> Option<T> {
+
+    let (reach_x, reach_y) = (7 * stride_x as u64, 7 * stride_y as u64);
+    let (x, y) = (x as u64, y as u64);
+    if x < reach_x || y < reach_y || x + reach_x >= width as u64 || y + reach_y >= height as u64 {
+        return None;
+    }
+
+    let (stride_x, stride_y) = (stride_x as i64, stride_y as i64);
+    let mut count = 0;
+    let mut sample = [T::zero(); 64];
+
+    for &dy in OFFSETS.iter() {
+        for &dx in OFFSETS.iter() {
+            let px = (x as i64 + stride_x * dx as i64) as u32;
+            let py = (y as i64 + stride_y * dy as i64) as u32;
+            sample[count] = get(px, py)?;
+            count += 1;
+        }
+    }
+
+    Some(sample)
+}
+
+/// Computes the discrepancy between the model and the normalised
+/// patch centred at each pixel of an image. Pixels too near the
+/// image boundary to sample a patch are given the value u16::MAX,
+/// so every pixel of an image smaller than 15x15 has this value.
+///
+/// Pixels are read with the column cache described in scan_patches,
+/// so each pixel is read far fewer times than in a naive scan.
+pub fn match_image<I>(image: &I, model: &PatchDescriptor) -> ImageBuffer<Luma<u16>, Vec<u16>>
+    where I: GenericImageView<Pixel=Luma<u8>> {
+
+    match_image_with(image, model, u16::MAX)
+}
+
+/// As match_image, but giving pixels too near the image boundary to
+/// sample a patch the value border rather than u16::MAX, e.g. so that
+/// they are not mistaken for poor matches when searching for maxima.
+pub fn match_image_with<I>(image: &I, model: &PatchDescriptor, border: u16) -> ImageBuffer<Luma<u16>, Vec<u16>>
+    where I: GenericImageView<Pixel=Luma<u8>> {
+
+    let (width, height) = image.dimensions();
+    let mut scores = ImageBuffer::from_pixel(width, height, Luma([border]));
+    scan_patches(image, |x, y, sample| {
+        let patch = quantise_patch(&normalise(sample));
+        scores.put_pixel(x, y, Luma([discrepancy(&patch, model)]));
+    });
+    scores
+}
+
+/// Calls f with every patch in the valid region of an image, in
+/// row-major order of centre. The eight sampled pixels of each column
+/// are read once per row and shared between the eight patches which
+/// use that column, rather than reading all 64 pixels afresh for
+/// every patch. Does nothing if the image is too small to sample a
+/// patch.
+fn scan_patches<I, F>(image: &I, mut f: F)
+    where I: GenericImageView<Pixel=Luma<u8>>, F: FnMut(u32, u32, &[u8; 64]) {
+
+    let (width, height) = image.dimensions();
+    let (x_min, y_min, x_max, y_max) = match valid_region(width, height) {
+        Some(region) => region,
+        None => return
+    };
+
+    let mut columns = vec![[0u8; 8]; width as usize];
+    for y in y_min..=y_max {
+        for (x, column) in columns.iter_mut().enumerate() {
+            for (j, &dy) in OFFSETS.iter().enumerate() {
+                column[j] = image.get_pixel(x as u32, (y as i64 + dy as i64) as u32)[0];
+            }
+        }
+        for x in x_min..=x_max {
+            let mut sample = [0u8; 64];
+            for (i, &dx) in OFFSETS.iter().enumerate() {
+                let column = &columns[(x as i64 + dx as i64) as usize];
+                for (j, &value) in column.iter().enumerate() {
+                    sample[8 * j + i] = value;
+                }
+            }
+            f(x, y, &sample);
+        }
+    }
+}
+
+/// As match_image, but scaled for display: a discrepancy of 0 is
+/// mapped to 255 and a discrepancy equal to the number of rare bins
+/// in the model, the worst possible, is mapped to 0. Pixels too near
+/// the image boundary to sample a patch are also given the value 0.
+pub fn match_image_normalised<I>(image: &I, model: &PatchDescriptor) -> ImageBuffer<Luma<u8>, Vec<u8>>
+    where I: GenericImageView<Pixel=Luma<u8>> {
+
+    let worst = max_discrepancy(model).max(1) as f32;
+    let scores = match_image(image, model);
+    ImageBuffer::from_fn(scores.width(), scores.height(), |x, y| {
+        let score = scores.get_pixel(x, y)[0];
+        if score == u16::MAX {
+            return Luma([0]);
+        }
+        let brightness = 255.0 * (1.0 - score as f32 / worst);
+        Luma([brightness.round().max(0.0) as u8])
+    })
+}
+
+/// Returns the sum of squared differences between two patches.
+#[must_use]
+pub fn ssd(a: &[u8; 64], b: &[u8; 64]) -> u32 {
+    a.iter().zip(b.iter()).fold(0, |acc, (&x, &y)| {
+        let d = x as i32 - y as i32;
+        acc + (d * d) as u32
+    })
+}
+
+/// Computes the sum of squared differences between the normalised
+/// reference patch and the normalised patch centred at each pixel of
+/// an image, as a continuous alternative to match_image. The reference
+/// should be a raw patch, e.g. as returned by sample_patch. Pixels too
+/// near the image boundary to sample a patch are given the value u32::MAX.
+pub fn match_image_ssd<I>(image: &I, reference: &[u8; 64]) -> ImageBuffer<Luma<u32>, Vec<u32>>
+    where I: GenericImageView<Pixel=Luma<u8>> {
+
+    let (width, height) = image.dimensions();
+    let mut scores = ImageBuffer::from_pixel(width, height, Luma([u32::MAX]));
+    let (x_min, y_min, x_max, y_max) = match valid_region(width, height) {
+        Some(region) => region,
+        None => return scores
+    };
+    let reference = normalise(reference);
+
+    for y in y_min..=y_max {
+        for x in x_min..=x_max {
+            if let Some(sample) = sample_patch(image, x, y) {
+                scores.put_pixel(x, y, Luma([ssd(&normalise(&sample), &reference)]));
+            }
+        }
+    }
+
+    scores
+}
+
+/// Returns the zero-mean normalised cross-correlation of two patches,
+/// in -1.0..=1.0. This is 1.0 for patches which differ only by
+/// brightness and contrast and -1.0 if one is the inverse of the
+/// other. A flat patch is uncorrelated with anything, so if either
+/// patch is flat the result is 0.0. The result is always finite,
+/// with 0.0 returned in place of any non-finite correlation.
+#[must_use]
+pub fn zncc(a: &[u8; 64], b: &[u8; 64]) -> f32 {
+    let (mean_a, stddev_a) = mean_and_stddev(a);
+    let (mean_b, stddev_b) = mean_and_stddev(b);
+    if is_flat(stddev_a) || is_flat(stddev_b) {
+        return 0.0;
+    }
+    let covariance = a.iter().zip(b.iter()).fold(0.0, |acc, (&x, &y)| {
+        acc + (x as f64 - mean_a) * (y as f64 - mean_b)
+    }) / 64.0;
+    finite_or((covariance / (stddev_a * stddev_b)).clamp(-1.0, 1.0) as f32, 0.0)
+}
+
+/// Computes the zero-mean normalised cross-correlation between the
+/// reference patch and the patch centred at each pixel of an image.
+/// Pixels too near the image boundary to sample a patch are given
+/// the value -1.0, the worst possible correlation.
+pub fn match_image_zncc<I>(image: &I, reference: &[u8; 64]) -> ImageBuffer<Luma<f32>, Vec<f32>>
+    where I: GenericImageView<Pixel=Luma<u8>> {
+
+    let (width, height) = image.dimensions();
+    let mut scores = ImageBuffer::from_pixel(width, height, Luma([-1.0]));
+    let (x_min, y_min, x_max, y_max) = match valid_region(width, height) {
+        Some(region) => region,
+        None => return scores
+    };
+
+    for y in y_min..=y_max {
+        for x in x_min..=x_max {
+            if let Some(sample) = sample_patch(image, x, y) {
+                scores.put_pixel(x, y, Luma([zncc(&sample, reference)]));
+            }
+        }
+    }
+
+    scores
+}
+
+/// Integral images of pixel intensities and their squares, from which
+/// the mean and standard deviation of the patch sampled at any point
+/// can be computed in constant time. Patches sample every other pixel,
+/// so each entry sums only the pixels whose coordinates have the same
+/// parities as its own.
+#[derive(Clone, Debug)]
+pub struct IntegralStats {
+    width: u32,
+    height: u32,
+    sums: Vec<u64>,
+    squares: Vec<u64>
+}
+
+/// Computes the integral images used by match_image_integral.
+pub fn prepare_integral<I>(image: &I) -> IntegralStats
+    where I: GenericImageView<Pixel=Luma<u8>> {
+
+    let (width, height) = image.dimensions();
+    let (w, h) = (width as usize, height as usize);
+    let mut sums = vec![0u64; w * h];
+    let mut squares = vec![0u64; w * h];
+
+    for y in 0..h {
+        for x in 0..w {
+            let p = image.get_pixel(x as u32, y as u32)[0] as u64;
+            let mut sum = p;
+            let mut square = p * p;
+            if x >= 2 {
+                sum += sums[y * w + x - 2];
+                square += squares[y * w + x - 2];
+            }
+            if y >= 2 {
+                sum += sums[(y - 2) * w + x];
+                square += squares[(y - 2) * w + x];
+            }
+            if x >= 2 && y >= 2 {
+                sum -= sums[(y - 2) * w + x - 2];
+                square -= squares[(y - 2) * w + x - 2];
+            }
+            sums[y * w + x] = sum;
+            squares[y * w + x] = square;
+        }
+    }
+
+    IntegralStats { width, height, sums, squares }
+}
+
+impl IntegralStats {
+
+    /// Returns the mean and population standard deviation of the
+    /// patch which sample_patch would return at (x, y), or None if
+    /// the patch cannot be sampled.
+    pub fn mean_and_stddev(&self, x: u32, y: u32) -> Option<(f64, f64)> {
+        let (x_min, y_min, x_max, y_max) = valid_region(self.width, self.height)?;
+        if x < x_min || y < y_min || x > x_max || y > y_max {
+            return None;
+        }
+        let sum = self.window_sum(&self.sums, x, y);
+        let square = self.window_sum(&self.squares, x, y);
+        // 64 * sum of squares - sum^2 is exactly 64^2 times the variance
+        let variance = (64 * square - sum * sum) as f64 / 4096.0;
+        Some((sum as f64 / 64.0, variance.sqrt()))
+    }
+
+    /// Sums the entries of the sampled 8x8 grid centred at (x, y).
+    fn window_sum(&self, integral: &[u64], x: u32, y: u32) -> u64 {
+        let w = self.width as usize;
+        let at = |x: i64, y: i64| {
+            if x < 0 || y < 0 { 0 } else { integral[y as usize * w + x as usize] }
+        };
+        let (x, y) = (x as i64, y as i64);
+        at(x + 7, y + 7) + at(x - 9, y - 9) - at(x - 9, y + 7) - at(x + 7, y - 9)
+    }
+}
+
+/// As match_image, but computing the mean and standard deviation used
+/// to normalise each patch in constant time from integral images
+/// rather than from the sampled pixels. Pixels are read with the same
+/// column cache as match_image. The scores are the same as those of
+/// match_image up to floating point rounding in the normalisation.
+pub fn match_image_integral<I>(image: &I, model: &PatchDescriptor) -> ImageBuffer<Luma<u16>, Vec<u16>>
+    where I: GenericImageView<Pixel=Luma<u8>> {
+
+    let (width, height) = image.dimensions();
+    let mut scores = ImageBuffer::from_pixel(width, height, Luma([u16::MAX]));
+    if valid_region(width, height).is_none() {
+        return scores;
+    }
+    let stats = prepare_integral(image);
+
+    scan_patches(image, |x, y, sample| {
+        if let Some((mean, stddev)) = stats.mean_and_stddev(x, y) {
+            let patch = quantise_patch(&normalise_with(sample, mean, stddev));
+            scores.put_pixel(x, y, Luma([discrepancy(&patch, model)]));
+        }
+    });
+
+    scores
+}
+
+/// As match_image, but scoring the rows of the image in parallel.
+#[cfg(feature = "rayon")]
+pub fn match_image_parallel<I>(image: &I, model: &PatchDescriptor) -> ImageBuffer<Luma<u16>, Vec<u16>>
+    where I: GenericImageView<Pixel=Luma<u8>> + Sync {
+
+    let (width, height) = image.dimensions();
+    let mut scores = vec![u16::MAX; width as usize * height as usize];
+    if valid_region(width, height).is_none() {
+        return ImageBuffer::from_raw(width, height, scores).unwrap();
+    }
+
+    scores.par_chunks_mut(width.max(1) as usize).enumerate().for_each(|(y, row)| {
+        for x in 0..width {
+            if let Some(score) = score_at(image, x, y as u32, model) {
+                row[x as usize] = score;
+            }
+        }
+    });
+
+    ImageBuffer::from_raw(width, height, scores).unwrap()
+}
+
+/// Returns the location with the lowest discrepancy against the model,
+/// as (x, y, discrepancy), or an error if the image is too small to
+/// sample any patch. Ties are resolved in favour of the lowest y, then
+/// the lowest x.
+pub fn find_best_match<I>(image: &I, model: &PatchDescriptor) -> Result<(u32, u32, Discrepancy), VisionError>
+    where I: GenericImageView<Pixel=Luma<u8>> {
+
+    let (x_min, y_min, x_max, y_max) = check_size(image)?;
+    let mut best: Option<(u32, u32, Discrepancy)> = None;
+
+    for y in y_min..=y_max {
+        for x in x_min..=x_max {
+            if let Some(patch) = patch_at(image, x, y) {
+                let d = score(&patch, model);
+                match best {
+                    Some((_, _, b)) if b <= d => {},
+                    _ => best = Some((x, y, d))
+                }
+            }
+        }
+    }
+
+    best.ok_or(VisionError::ImageTooSmall)
+}
+
+/// Returns the location with the lowest discrepancy against each of
+/// the given models, as (x, y, discrepancy), with ties resolved as in
+/// find_best_match. Each patch is sampled and quantised once and then
+/// compared against every model, which is much cheaper than calling
+/// find_best_match once per model. An entry is None if no patch could
+/// be compared against its model, e.g. because the image is too small.
+pub fn match_templates<I>(image: &I, models: &[PatchDescriptor]) -> Vec<Option<(u32, u32, u16)>>
+    where I: GenericImageView<Pixel=Luma<u8>> {
+
+    let mut best: Vec<Option<(u32, u32, u16)>> = vec![None; models.len()];
+    let (x_min, y_min, x_max, y_max) = match check_size(image) {
+        Ok(bounds) => bounds,
+        Err(_) => return best
+    };
+
+    for y in y_min..=y_max {
+        for x in x_min..=x_max {
+            if let Some(patch) = patch_at(image, x, y) {
+                for (model, b) in models.iter().zip(best.iter_mut()) {
+                    let score = discrepancy(&patch, model);
+                    match *b {
+                        Some((_, _, s)) if s <= score => {},
+                        _ => *b = Some((x, y, score))
+                    }
+                }
+            }
+        }
+    }
+
+    best
+}
+
+/// The largest number of pixels in a resized image which
+/// match_pyramid will allocate.
+const MAX_PYRAMID_PIXELS: f64 = (1u64 << 28) as f64;
+
+/// Runs find_best_match on copies of the image resized by each of the
+/// given scales, and returns the best match across all scales as
+/// (x, y, scale, discrepancy). A model trained on objects of a given
+/// size will match an object twice that size at scale 0.5.
+///
+/// The returned coordinates are in the original image, found by
+/// dividing the coordinates in the resized image by the scale and
+/// rounding to the nearest pixel. Ties are resolved in favour of the
+/// scale listed first. Non-positive and non-finite scales are ignored,
+/// as are scales which would resize the image to more than 2^28 pixels.
+pub fn match_pyramid<I>(image: &I, model: &PatchDescriptor, scales: &[f32]) -> Option<(u32, u32, f32, u16)>
+    where I: GenericImageView<Pixel=Luma<u8>> {
+
+    let (width, height) = image.dimensions();
+    let mut best: Option<(u32, u32, f32, u16)> = None;
+
+    for &scale in scales {
+        if !scale.is_finite() || scale <= 0.0 {
+            continue;
+        }
+        let scaled_width = (width as f64 * scale as f64).round();
+        let scaled_height = (height as f64 * scale as f64).round();
+        if scaled_width * scaled_height > MAX_PYRAMID_PIXELS {
+            continue;
+        }
+        let scaled = resize(image, scaled_width as u32, scaled_height as u32, FilterType::Triangle);
+
+        if let Ok((x, y, Discrepancy(score))) = find_best_match(&scaled, model) {
+            match best {
+                Some((_, _, _, s)) if s <= score => {},
+                _ => {
+                    let x = (x as f32 / scale).round() as u32;
+                    let y = (y as f32 / scale).round() as u32;
+                    best = Some((x, y, scale, score));
+                }
+            }
+        }
+    }
+
+    best
+}
+
+/// Returns every location whose discrepancy against the model is at
+/// most max_discrepancy, as (x, y, discrepancy), in row-major order.
+/// Returns an error if the image is too small to sample any patch.
+pub fn detect_matches<I>(image: &I, model: &PatchDescriptor, max_discrepancy: u16) -> Result<Vec<(u32, u32, u16)>, VisionError>
+    where I: GenericImageView<Pixel=Luma<u8>> {
+
+    let (x_min, y_min, x_max, y_max) = check_size(image)?;
+    let mut matches = Vec::new();
+    for y in y_min..=y_max {
+        matches.extend(detect_matches_in_row(image, y, x_min, x_max, model, max_discrepancy));
+    }
+    Ok(matches)
+}
+
+/// Returns the discrepancy at every centre in the region of interest
+/// roi = (x, y, width, height), as (x, y, discrepancy) in row-major
+/// order. The region is clamped to the image bounds and intersected
+/// with the valid region, so centres too near the image boundary are
+/// never reported. Returns no matches if the clamped region is empty,
+/// and an error if the image is too small to sample any patch.
+pub fn match_image_roi<I>(image: &I, model: &PatchDescriptor, roi: (u32, u32, u32, u32)) -> Result<Vec<(u32, u32, u16)>, VisionError>
+    where I: GenericImageView<Pixel=Luma<u8>> {
+
+    let (x_min, y_min, x_max, y_max) = check_size(image)?;
+    let (roi_x, roi_y, roi_width, roi_height) = roi;
+    if roi_width == 0 || roi_height == 0 {
+        return Ok(vec![]);
+    }
+
+    let x_min = x_min.max(roi_x);
+    let y_min = y_min.max(roi_y);
+    let x_max = x_max.min(roi_x.saturating_add(roi_width - 1));
+    let y_max = y_max.min(roi_y.saturating_add(roi_height - 1));
+    if x_min > x_max || y_min > y_max {
+        return Ok(vec![]);
+    }
+
+    let mut matches = Vec::new();
+    for y in y_min..=y_max {
+        for x in x_min..=x_max {
+            if let Some(score) = score_at(image, x, y, model) {
+                matches.push((x, y, score));
+            }
+        }
+    }
+    Ok(matches)
+}
+
+/// Returns the discrepancy at every centre whose sampled patch has a
+/// standard deviation of at least min_contrast, as (x, y, discrepancy)
+/// in row-major order. Low contrast patches normalise to little more
+/// than noise, or to mid-gray if flat, so can match a bland model
+/// well without resembling it; these are rejected before scoring.
+/// As with match_templates, returns no matches if the image is too
+/// small to sample any patch.
+pub fn match_image_confident<I>(image: &I, model: &PatchDescriptor, min_contrast: f64) -> Vec<(u32, u32, u16)>
+    where I: GenericImageView<Pixel=Luma<u8>> {
+
+    let (x_min, y_min, x_max, y_max) = match check_size(image) {
+        Ok(bounds) => bounds,
+        Err(_) => return vec![]
+    };
+
+    let mut matches = Vec::new();
+    for y in y_min..=y_max {
+        for x in x_min..=x_max {
+            if let Some(sample) = sample_patch(image, x, y) {
+                let (mean, stddev) = mean_and_stddev(&sample);
+                if stddev < min_contrast {
+                    continue;
+                }
+                let patch = quantise_patch(&normalise_with(&sample, mean, stddev));
+                matches.push((x, y, discrepancy(&patch, model)));
+            }
+        }
+    }
+    matches
+}
+
+/// As detect_matches, but scanning the rows of the image in parallel.
+/// Matches are returned in the same row-major order.
+#[cfg(feature = "rayon")]
+pub fn detect_matches_parallel<I>(image: &I, model: &PatchDescriptor, max_discrepancy: u16) -> Result<Vec<(u32, u32, u16)>, VisionError>
+    where I: GenericImageView<Pixel=Luma<u8>> + Sync {
+
+    let (x_min, y_min, x_max, y_max) = check_size(image)?;
+    Ok((y_min..=y_max).into_par_iter()
+        .map(|y| detect_matches_in_row(image, y, x_min, x_max, model, max_discrepancy))
+        .collect::<Vec<_>>()
+        .concat())
+}
+
+fn detect_matches_in_row<I>(image: &I, y: u32, x_min: u32, x_max: u32, model: &PatchDescriptor, max_discrepancy: u16) -> Vec<(u32, u32, u16)>
+    where I: GenericImageView<Pixel=Luma<u8>> {
+
+    let mut matches = Vec::new();
+    for x in x_min..=x_max {
+        if let Some(patch) = patch_at(image, x, y) {
+            if let Some(score) = discrepancy_bounded(&patch, model, max_discrepancy) {
+                matches.push((x, y, score));
+            }
+        }
+    }
+    matches
+}
+
+/// Discards every detection for which another detection within
+/// Euclidean distance radius has a lower discrepancy. Where two nearby
+/// detections have equal discrepancy, only the one appearing first
+/// in detections is kept. Survivors are returned in their input order.
+pub fn suppress_non_minima(detections: &[(u32, u32, u16)], radius: u32) -> Vec<(u32, u32, u16)> {
+    let radius_squared = radius as u64 * radius as u64;
+    let mut minima = Vec::new();
+
+    for (i, &(x, y, score)) in detections.iter().enumerate() {
+        let suppressed = detections.iter().enumerate().any(|(j, &(ox, oy, other))| {
+            let dx = x as i64 - ox as i64;
+            let dy = y as i64 - oy as i64;
+            let near = j != i && ((dx * dx + dy * dy) as u64) <= radius_squared;
+            near && (other < score || (other == score && j < i))
+        });
+        if !suppressed {
+            minima.push((x, y, score));
+        }
+    }
+
+    minima
+}
+
+/// Computes the discrepancy between the model and the normalised
+/// patch centred at (x, y), or None if (x, y) is too near the
+/// image boundary.
+fn score_at<I>(image: &I, x: u32, y: u32, model: &PatchDescriptor) -> Option<u16>
+    where I: GenericImageView<Pixel=Luma<u8>> {
+
+    patch_at(image, x, y).map(|patch| discrepancy(&patch, model))
+}
+
+fn patch_at<I>(image: &I, x: u32, y: u32) -> Option<PatchDescriptor>
+    where I: GenericImageView<Pixel=Luma<u8>> {
+
+    sample_patch(image, x, y).map(|sample| quantise_patch(&normalise(&sample)))
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+
+    use image::{GenericImageView,ImageBuffer,Luma,Rgb};
+    use std::cell::Cell;
+    use super::{
+        bin,
+        bin_f32,
+        demean,
+        descriptor_and,
+        descriptor_from_bytes,
+        descriptor_or,
+        descriptor_to_bytes,
+        descriptor_to_features,
+        descriptor_to_image,
+        detect_matches,
+        discrepancy,
+        Discrepancy,
+        discrepancy_bounded,
+        discrepancy_masked,
+        empty_bins,
+        find_best_match,
+        grid_keypoints,
+        Descriptor,
+        hamming,
+        match_image,
+        match_image_integral,
+        match_image_confident,
+        match_image_normalised,
+        match_image_roi,
+        match_image_with,
+        match_image_ssd,
+        match_image_zncc,
+        match_pyramid,
+        match_templates,
+        max_discrepancy,
+        mean_and_stddev,
+        normalise,
+        normalise_f32,
+        prepare_integral,
+        quantise_many,
+        quantise_patch,
+        reflect,
+        sample_patch,
+        sample_patch_aniso,
+        sample_patch_order,
+        sample_patch_reflect,
+        sample_patch_rgb,
+        sample_patch_strided,
+        sample_patch_subpixel,
+        set_bit,
+        set_bit_multi,
+        similarity,
+        Similarity,
+        ssd,
+        zncc,
+        suppress_non_minima,
+        valid_region,
+        weighted_discrepancy,
+        popcount,
+        rare_density,
+        score,
+        score_at,
+        score_similarity,
+        FractionRule,
+        MaskedDescriptor,
+        PatchDescriptor,
+        PatchSource,
+        SampleOrder,
+        PatchModel,
+        PointHist,
+        PointHistExt,
+        RarityRule
+    };
+    use error::VisionError;
+    use rand::{Rng,SeedableRng};
+    use rand::rngs::StdRng;
+
+    /// An all-zero image containing a copy of the same 15x15
+    /// block of texture centred at each of the given points.
+    pub(crate) fn planted_image(width: u32, height: u32, centres: &[(u32, u32)]) -> ImageBuffer<Luma<u8>, Vec<u8>> {
+        ImageBuffer::from_fn(width, height, |x, y| {
+            for &(cx, cy) in centres {
+                if x + 7 >= cx && x <= cx + 7 && y + 7 >= cy && y <= cy + 7 {
+                    let (u, v) = (x + 7 - cx, y + 7 - cy);
+                    return Luma([((u * 73 + v * 151 + u * v * 37) % 251) as u8]);
+                }
+            }
+            Luma([0u8])
+        })
+    }
+
+    /// The descriptor of a model trained on the patches of an
+    /// image centred at each of the given points.
+    fn trained_descriptor(image: &ImageBuffer<Luma<u8>, Vec<u8>>, centres: &[(u32, u32)]) -> PatchDescriptor {
+        let mut model = PatchModel::new();
+        for &(x, y) in centres {
+            model.add_normalised_sample(sample_patch(image, x, y).unwrap());
+        }
+        model.quantise()
+    }
+
+    fn random_patch(rng: &mut StdRng) -> [u8; 64] {
+        let mut patch = [0u8; 64];
+        rng.fill(&mut patch[..]);
+        patch
+    }
+
+    fn random_model(rng: &mut StdRng, n_samples: usize) -> PatchModel {
+        let mut model = PatchModel::new();
+        for _ in 0..n_samples {
+            model.add_normalised_sample(random_patch(rng));
+        }
+        model
+    }
+
+    #[test]
+    fn test_property_model_matches_its_training_patches() {
+        let mut rng = StdRng::seed_from_u64(71);
+        for _ in 0..200 {
+            let patches = (0..rng.gen_range(1..4)).map(|_| random_patch(&mut rng)).collect::<Vec<_>>();
+            let mut model = PatchModel::new();
+            for p in &patches {
+                model.add_normalised_sample(p);
+            }
+            // Every bin seen in training holds at least a third of the samples
+            let descriptor = model.quantise();
+            for p in &patches {
+                assert_eq!(discrepancy(&quantise_patch(&normalise(p)), &descriptor), 0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_property_hamming_is_a_metric() {
+        let mut rng = StdRng::seed_from_u64(72);
+        for _ in 0..200 {
+            let a = random_model(&mut rng, 5).quantise();
+            let b = random_model(&mut rng, 5).quantise();
+            let c = random_model(&mut rng, 5).quantise();
+            assert_eq!(hamming(&a, &a), 0);
+            assert_eq!(hamming(&a, &b), hamming(&b, &a));
+            assert!(hamming(&a, &c) <= hamming(&a, &b) + hamming(&b, &c));
+        }
+    }
+
+    #[test]
+    fn test_property_discrepancy_is_bounded() {
+        let mut rng = StdRng::seed_from_u64(73);
+        for _ in 0..200 {
+            let n_samples = rng.gen_range(0..20);
+            let model = random_model(&mut rng, n_samples).quantise();
+            let patch = quantise_patch(&normalise(&random_patch(&mut rng)));
+            let score = discrepancy(&patch, &model);
+            assert!(score <= max_discrepancy(&model));
+            assert!(score <= 64);
+            assert!((0.0..=1.0).contains(&similarity(&patch, &model)));
+        }
+    }
+
+    #[test]
+    fn test_bin() {
+        assert_eq!(bin(0u8, 5), 0);
+        assert_eq!(bin(51u8, 5), 0);
+        assert_eq!(bin(52u8, 5), 1);
+        assert_eq!(bin(102u8, 5), 1);
+        assert_eq!(bin(103u8, 5), 2);
+        assert_eq!(bin(153u8, 5), 2);
+        assert_eq!(bin(154u8, 5), 3);
+        assert_eq!(bin(204u8, 5), 3);
+        assert_eq!(bin(205u8, 5), 4);
+        assert_eq!(bin(255u8, 5), 4);
+    }
+
+    #[test]
+    fn test_bin_counts() {
+        assert_eq!(bin(63u8, 4), 0);
+        assert_eq!(bin(64u8, 4), 1);
+        assert_eq!(bin(191u8, 4), 2);
+        assert_eq!(bin(255u8, 4), 3);
+
+        assert_eq!(bin(31u8, 8), 0);
+        assert_eq!(bin(32u8, 8), 1);
+        assert_eq!(bin(100u8, 8), 3);
+        assert_eq!(bin(255u8, 8), 7);
+    }
+
+    #[test]
+    fn test_quantise_words_eight_bins() {
+        let mut model = PatchModel::<64, 8>::default();
+        let mut sample = [0u8; 64];
+        sample[0] = 255;
+        model.add_sample(sample);
+        assert_eq!(model.hists[0], [0, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(model.hists[1], [1, 0, 0, 0, 0, 0, 0, 0]);
+
+        let words = model.quantise_words();
+        assert_eq!(words.len(), 8);
+        assert_eq!(words[0], 1);
+        assert_eq!(&words[1..7], &[!0u64; 6][..]);
+        assert_eq!(words[7], !1);
+    }
+
+    #[test]
+    fn test_discrepancy() {
+        assert_eq!(discrepancy(&[0; 5], &[!0; 5]), 0);
+        assert_eq!(discrepancy(&[!0; 5], &[!0; 5]), 320);
+        assert_eq!(discrepancy(&[0b1011, 0, 1 << 63, 0, 0], &[0b0110, !0, !0, 0, 1]), 2);
+    }
+
+    #[test]
+    fn test_discrepancy_matches_scalar_loop() {
+        let mut rng = StdRng::seed_from_u64(42);
+        for _ in 0..10000 {
+            let patch: PatchDescriptor = rng.gen();
+            let model: PatchDescriptor = rng.gen();
+            let expected = patch.iter().zip(model.iter())
+                .map(|(p, m)| (p & m).count_ones() as u16)
+                .sum::<u16>();
+            assert_eq!(discrepancy(&patch, &model), expected);
+        }
+    }
+
+    #[test]
+    fn test_count_ones_matches_bit_loop() {
+        let mut rng = StdRng::seed_from_u64(62);
+        for _ in 0..10000 {
+            let n: u64 = rng.gen();
+            let mut expected = 0;
+            for i in 0..64 {
+                if n & (1 << i) != 0 {
+                    expected += 1;
+                }
+            }
+            assert_eq!(n.count_ones(), expected);
+        }
+    }
+
+    #[test]
+    fn test_discrepancy_bounded() {
+        let mut rng = StdRng::seed_from_u64(7);
+        for _ in 0..1000 {
+            let patch: PatchDescriptor = rng.gen();
+            let model: PatchDescriptor = rng.gen();
+            let max = rng.gen_range(0..321);
+            let full = discrepancy(&patch, &model);
+            let expected = if full <= max { Some(full) } else { None };
+            assert_eq!(discrepancy_bounded(&patch, &model, max), expected);
+        }
+        assert_eq!(discrepancy_bounded(&[!0; 5], &[!0; 5], 320), Some(320));
+        assert_eq!(discrepancy_bounded(&[!0; 5], &[!0; 5], 319), None);
+    }
+
+    #[test]
+    fn test_discrepancy_masked() {
+        let patch = [0b0111, 0, 1 << 40, 0, 0];
+        let model = [0b0101, !0, !0, 0, 0];
+        assert_eq!(discrepancy(&patch, &model), 3);
+
+        let all = MaskedDescriptor { descriptor: model, active: !0 };
+        assert_eq!(discrepancy_masked(&patch, &all), 3);
+
+        let partial = MaskedDescriptor { descriptor: model, active: !(1 << 40) };
+        assert_eq!(discrepancy_masked(&patch, &partial), 2);
+
+        let occluded = MaskedDescriptor { descriptor: model, active: !(0b0101 | 1 << 40) };
+        assert_eq!(discrepancy_masked(&patch, &occluded), 0);
+    }
+
+    #[test]
+    fn test_set_bit() {
+        assert_eq!(set_bit(0, 0), 1);
+        assert_eq!(set_bit(0, 1), 2);
+        assert_eq!(set_bit(0, 2), 4);
+        assert_eq!(set_bit(1, 1), 3);
+        assert_eq!(set_bit(0, 63), 1 << 63);
+        assert_eq!(set_bit(5, 64), 5);
+        assert_eq!(set_bit(5, 255), 5);
+    }
+
+    #[test]
+    fn test_set_bit_multi() {
+        let mut words = [0u64; 2];
+        set_bit_multi(&mut words, 63);
+        assert_eq!(words, [1 << 63, 0]);
+        set_bit_multi(&mut words, 64);
+        assert_eq!(words, [1 << 63, 1]);
+        set_bit_multi(&mut words, 127);
+        assert_eq!(words, [1 << 63, 1 << 63 | 1]);
+        set_bit_multi(&mut words, 128);
+        assert_eq!(words, [1 << 63, 1 << 63 | 1]);
+    }
+
+    #[test]
+    fn test_new_model_quantise() {
+        let model = PatchModel::new();
+        assert_eq!(model.quantise(), [!0u64; 5]);
+    }
+
+    #[test]
+    fn test_quantise_untrained_locations() {
+        let mut model = PatchModel::new();
+        model.hists[0] = [10, 0, 0, 0, 0];
+        model.hists[1] = [0, 0, 10, 0, 0];
+        let descriptor = model.quantise();
+        // Locations 0 and 1 are trained, all others are not.
+        let untrained = !0u64 << 2;
+        assert_eq!(descriptor, [
+            untrained | 2,
+            untrained | 3,
+            untrained | 1,
+            untrained | 3,
+            untrained | 3
+        ]);
+    }
+
+    #[test]
+    fn test_quantise_into() {
+        let mut model = PatchModel::new();
+        model.add_sample([0; 64]);
+        model.add_sample([120; 64]);
+
+        let mut out = [!0u64; 5];
+        model.quantise_into(&mut out);
+        assert_eq!(out, model.quantise());
+        PatchModel::new().quantise_into(&mut out);
+        assert_eq!(out, PatchModel::new().quantise());
+    }
+
+    #[test]
+    fn test_quantise_many() {
+        let mut models = vec![PatchModel::new(); 3];
+        models[1].add_sample([0; 64]);
+        models[2].add_sample([200; 64]);
+        models[2].add_sample([100; 64]);
+
+        let expected = models.iter().map(|m| m.quantise()).collect::<Vec<_>>();
+        assert_eq!(quantise_many(&models), expected);
+        assert!(quantise_many(&[]).is_empty());
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_quantise_many_parallel() {
+        use super::quantise_many_parallel;
+
+        let mut models = vec![PatchModel::new(); 20];
+        for (i, m) in models.iter_mut().enumerate() {
+            m.add_sample([(i * 12) as u8; 64]);
+        }
+        assert_eq!(quantise_many_parallel(&models), quantise_many(&models));
+    }
+
+    #[test]
+    fn test_quantise_single_sample() {
+        let mut model = PatchModel::new();
+        model.add_sample([0u8; 64]);
+        assert_eq!(model.quantise(), [0, !0, !0, !0, !0]);
+    }
+
+    #[test]
+    fn test_sample_patch() {
+        let image: ImageBuffer<Luma<u8>, Vec<u8>> =
+            ImageBuffer::from_fn(16, 16, |x, y| Luma([(x + 16 * y) as u8]));
+
+        let sample = sample_patch(&image, 7, 7).unwrap();
+        for j in 0..8 {
+            for i in 0..8 {
+                assert_eq!(sample[8 * j + i], (2 * i + 32 * j) as u8);
+            }
+        }
+    }
+
+    #[test]
+    fn test_sample_patch_matches_reference() {
+        let image = ImageBuffer::from_fn(40, 30, |x, y| Luma([((x * 37 + y * 11) % 256) as u8]));
+        for &(x, y) in &[(7, 7), (20, 15), (32, 22)] {
+            let mut expected = [0u8; 64];
+            for j in 0..8 {
+                for i in 0..8 {
+                    expected[8 * j + i] = image.get_pixel(x + 2 * i as u32 - 7, y + 2 * j as u32 - 7)[0];
+                }
+            }
+            assert_eq!(sample_patch(&image, x, y), Some(expected));
+        }
+    }
+
+    #[test]
+    fn test_sample_patch_order() {
+        let image = ImageBuffer::from_fn(30, 30, |x, y| Luma([(x * 7 + y) as u8]));
+        let rows = sample_patch_order::<_, u8>(&image, 15, 15, SampleOrder::RowMajor).unwrap();
+        let cols = sample_patch_order::<_, u8>(&image, 15, 15, SampleOrder::ColMajor).unwrap();
+
+        assert_eq!(Some(rows), sample_patch(&image, 15, 15));
+        assert_ne!(rows, cols);
+        for j in 0..8 {
+            for i in 0..8 {
+                assert_eq!(cols[8 * i + j], rows[8 * j + i]);
+            }
+        }
+        // Moving along a row increases x, so by 14 with each step
+        assert_eq!(rows[1] - rows[0], 14);
+        assert_eq!(cols[1] - cols[0], 2);
+        assert_eq!(sample_patch_order::<_, u8>(&image, 3, 15, SampleOrder::ColMajor), None);
+    }
+
+    #[test]
+    fn test_valid_region() {
+        assert_eq!(valid_region(14, 14), None);
+        assert_eq!(valid_region(15, 14), None);
+        assert_eq!(valid_region(0, 0), None);
+        assert_eq!(valid_region(15, 15), Some((7, 7, 7, 7)));
+        assert_eq!(valid_region(30, 20), Some((7, 7, 22, 12)));
+
+        let image: ImageBuffer<Luma<u8>, Vec<u8>> = ImageBuffer::new(30, 20);
+        for y in 0..20 {
+            for x in 0..30 {
+                let inside = (7..=22).contains(&x) && (7..=12).contains(&y);
+                assert_eq!(sample_patch::<_, u8>(&image, x, y).is_some(), inside);
+            }
+        }
+    }
+
+    #[test]
+    fn test_sample_patch_near_boundary() {
+        let image: ImageBuffer<Luma<u8>, Vec<u8>> = ImageBuffer::new(16, 16);
+        assert!(sample_patch(&image, 6, 7).is_none());
+        assert!(sample_patch(&image, 7, 6).is_none());
+        assert!(sample_patch(&image, 9, 8).is_none());
+        assert!(sample_patch(&image, 8, 9).is_none());
+        assert!(sample_patch(&image, 8, 8).is_some());
+    }
+
+    #[test]
+    fn test_add_normalised_sample_ignores_offset() {
+        let mut patch = [0u8; 64];
+        let mut brighter = [0u8; 64];
+        for i in 0..64 {
+            patch[i] = (i * 3) as u8;
+            brighter[i] = patch[i] + 20;
+        }
+
+        let mut model = PatchModel::new();
+        model.add_normalised_sample(patch);
+        let mut brighter_model = PatchModel::new();
+        brighter_model.add_normalised_sample(brighter);
+
+        assert_eq!(model.hists, brighter_model.hists);
+    }
+
+    #[test]
+    fn test_add_sample_demeaned_ignores_offset() {
+        let mut raw = [0u8; 64];
+        for (i, r) in raw.iter_mut().enumerate() {
+            *r = (60 + (i * 7) % 50) as u8;
+        }
+        let mut brighter = raw;
+        for v in brighter.iter_mut() {
+            *v += 90;
+        }
+
+        let mut a = PatchModel::new();
+        let mut b = PatchModel::new();
+        a.add_sample_demeaned(&raw);
+        b.add_sample_demeaned(&brighter);
+        assert_eq!(a.quantise(), b.quantise());
+
+        // Flat patches are centred at mid-gray
+        assert_eq!(demean(&[17u8; 64]), [128u8; 64]);
+    }
+
+    #[test]
+    fn test_normalise_preserves_sign() {
+        let mut patch = [0u8; 64];
+        for p in &mut patch[32..] {
+            *p = 200;
+        }
+
+        let normalised = normalise(&patch);
+        assert_eq!(normalised[0], 96);
+        assert_eq!(normalised[63], 160);
+
+        let mut model = PatchModel::new();
+        model.add_normalised_sample(patch);
+        assert_eq!(model.hists[0], [0, 1, 0, 0, 0]);
+        assert_eq!(model.hists[63], [0, 0, 0, 1, 0]);
+    }
+
+    #[test]
+    fn test_normalise_f32_and_bin_f32() {
+        // Mean 120 and standard deviation 20
+        let mut patch = [100u8; 64];
+        for i in 0..32 {
+            patch[2 * i + 1] = 140;
+        }
+        let z = normalise_f32(&patch);
+        assert_eq!(z[0], -1.0);
+        assert_eq!(z[1], 1.0);
+        assert_eq!(bin_f32(z[0], 5), 1);
+        assert_eq!(bin_f32(z[1], 5), 3);
+        assert_eq!(bin_f32(0.0, 5), 2);
+        assert_eq!(bin_f32(-10.0, 5), 0);
+        assert_eq!(bin_f32(3.0, 5), 4);
+        assert_eq!(bin_f32(10.0, 5), 4);
+        assert_eq!(normalise_f32(&[7u8; 64]), [0.0; 64]);
+
+        let mut model = PatchModel::new();
+        model.add_normalised_sample_f32(&patch);
+        assert_eq!(model.histogram(0), Some(&[0, 1, 0, 0, 0]));
+        assert_eq!(model.histogram(1), Some(&[0, 0, 0, 1, 0]));
+    }
+
+    #[test]
+    fn test_normalise_flat_patch() {
+        let patch = [37u8; 64];
+        assert_eq!(&normalise(&patch)[..], &[128u8; 64][..]);
+
+        let mut model = PatchModel::new();
+        model.add_normalised_sample(patch);
+        for h in 0..64 {
+            assert_eq!(model.hists[h], [0, 0, 1, 0, 0]);
+        }
+    }
+
+    #[test]
+    fn test_quantise_patch() {
+        let mut patch = [0u8; 64];
+        patch[1] = 60;
+        patch[2] = 255;
+        assert_eq!(quantise_patch(&patch), [!0u64 ^ 6, 2, 0, 0, 4]);
+    }
+
+    #[test]
+    fn test_match_image() {
+        let image = planted_image(32, 24, &[(20, 12)]);
+        let mut model = PatchModel::new();
+        model.add_normalised_sample(sample_patch(&image, 20, 12).unwrap());
+
+        let scores = match_image(&image, &model.quantise());
+        assert_eq!(scores.get_pixel(20, 12)[0], 0);
+        for y in 0..24 {
+            for x in 0..32 {
+                let score = scores.get_pixel(x, y)[0];
+                if x < 7 || y < 7 || x + 7 >= 32 || y + 7 >= 24 {
+                    assert_eq!(score, u16::MAX);
+                } else if (x, y) != (20, 12) {
+                    assert!(score > 0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_match_image_normalised() {
+        let image = planted_image(60, 50, &[(30, 25)]);
+        let descriptor = trained_descriptor(&image, &[(30, 25)]);
+
+        let heatmap = match_image_normalised(&image, &descriptor);
+        assert_eq!(heatmap.dimensions(), (60, 50));
+        assert_eq!(heatmap.get_pixel(30, 25)[0], 255);
+        assert!(heatmap.get_pixel(12, 40)[0] < 255);
+        assert_eq!(heatmap.get_pixel(0, 0)[0], 0);
+        assert_eq!(heatmap.get_pixel(59, 49)[0], 0);
+    }
+
+    #[test]
+    fn test_integral_stats() {
+        let image = ImageBuffer::from_fn(30, 25, |x, y| Luma([((x * 37 + y * 11 + x * y) % 256) as u8]));
+        let stats = prepare_integral(&image);
+        for &(x, y) in &[(7, 7), (8, 7), (7, 8), (15, 12), (22, 17)] {
+            let (mean, stddev) = mean_and_stddev(&sample_patch::<_, u8>(&image, x, y).unwrap());
+            let (fast_mean, fast_stddev) = stats.mean_and_stddev(x, y).unwrap();
+            assert!((mean - fast_mean).abs() < 1e-9);
+            assert!((stddev - fast_stddev).abs() < 1e-9);
+        }
+        assert_eq!(stats.mean_and_stddev(6, 10), None);
+        assert_eq!(stats.mean_and_stddev(10, 18), None);
+        assert_eq!(stats.mean_and_stddev(u32::MAX, 10), None);
+        assert_eq!(stats.mean_and_stddev(10, u32::MAX), None);
+    }
+
+    #[test]
+    fn test_match_image_integral() {
+        let image = ImageBuffer::from_fn(48, 40, |x, y| Luma([(3 * x + 2 * y + (x * y) % 7) as u8]));
+        let descriptor = trained_descriptor(&image, &[(20, 20)]);
+
+        let naive = match_image(&image, &descriptor);
+        let fast = match_image_integral(&image, &descriptor);
+        for (a, b) in naive.pixels().zip(fast.pixels()) {
+            assert!((a[0] as i32 - b[0] as i32).abs() <= 1);
+        }
+        assert_eq!(fast.get_pixel(20, 20)[0], 0);
+        assert_eq!(fast.get_pixel(3, 3)[0], u16::MAX);
+
+        // One read per pixel to build the integral images, then the
+        // same column reads as match_image for each of the 26 valid rows
+        let counting = CountingImage { image: &image, reads: Cell::new(0) };
+        let counted = match_image_integral(&counting, &descriptor);
+        assert_eq!(counted.into_raw(), fast.into_raw());
+        assert_eq!(counting.reads.get(), 48 * 40 + 26 * 48 * 8);
+    }
+
+    /// Wraps an image, counting the number of pixels read from it.
+    struct CountingImage<'a> {
+        image: &'a ImageBuffer<Luma<u8>, Vec<u8>>,
+        reads: Cell<usize>
+    }
+
+    impl<'a> GenericImageView for CountingImage<'a> {
+        type Pixel = Luma<u8>;
+
+        fn dimensions(&self) -> (u32, u32) {
+            self.image.dimensions()
+        }
+
+        fn bounds(&self) -> (u32, u32, u32, u32) {
+            let (width, height) = self.image.dimensions();
+            (0, 0, width, height)
+        }
+
+        fn get_pixel(&self, x: u32, y: u32) -> Luma<u8> {
+            self.reads.set(self.reads.get() + 1);
+            *self.image.get_pixel(x, y)
+        }
+    }
+
+    #[test]
+    fn test_match_image_with() {
+        let image = planted_image(40, 30, &[(20, 15)]);
+        let descriptor = trained_descriptor(&image, &[(20, 15)]);
+
+        let default = match_image(&image, &descriptor);
+        let scores = match_image_with(&image, &descriptor, 999);
+        for (x, y, p) in scores.enumerate_pixels() {
+            let inside = (7..=32).contains(&x) && (7..=22).contains(&y);
+            if inside {
+                assert_eq!(p[0], default.get_pixel(x, y)[0]);
+                assert!(p[0] <= 64);
+            } else {
+                assert_eq!(p[0], 999);
+                assert_eq!(default.get_pixel(x, y)[0], u16::MAX);
+            }
+        }
+        assert_eq!(scores.get_pixel(20, 15)[0], 0);
+    }
+
+    #[test]
+    fn test_match_image_reuses_column_reads() {
+        let image = planted_image(64, 48, &[(20, 12), (45, 30)]);
+        let descriptor = trained_descriptor(&image, &[(20, 12)]);
+
+        let counting = CountingImage { image: &image, reads: Cell::new(0) };
+        let naive = ImageBuffer::from_fn(64, 48, |x, y| {
+            Luma([score_at(&counting, x, y, &descriptor).unwrap_or(u16::MAX)])
+        });
+        let naive_reads = counting.reads.replace(0);
+
+        let scores = match_image(&counting, &descriptor);
+        let reads = counting.reads.get();
+
+        assert_eq!(scores.into_raw(), naive.into_raw());
+        // 34 valid rows, reading each of 64 columns once per row,
+        // against 64 reads for each of the 50 * 34 valid patches
+        assert_eq!(reads, 34 * 64 * 8);
+        assert_eq!(naive_reads, 50 * 34 * 64);
+    }
+
+    #[test]
+    fn test_ssd() {
+        assert_eq!(ssd(&[0; 64], &[0; 64]), 0);
+        assert_eq!(ssd(&[0; 64], &[255; 64]), 64 * 255 * 255);
+        let mut a = [10u8; 64];
+        a[5] = 13;
+        a[60] = 6;
+        assert_eq!(ssd(&a, &[10; 64]), 9 + 16);
+        assert_eq!(ssd(&[10; 64], &a), 9 + 16);
+    }
+
+    #[test]
+    fn test_match_image_ssd() {
+        let image = planted_image(60, 50, &[(30, 25)]);
+        let reference = sample_patch(&image, 30, 25).unwrap();
+        let scores = match_image_ssd(&image, &reference);
+
+        assert_eq!(scores.get_pixel(30, 25)[0], 0);
+        assert!(scores.get_pixel(31, 25)[0] > 0);
+        assert!(scores.get_pixel(12, 40)[0] > 0);
+        assert_eq!(scores.get_pixel(0, 0)[0], u32::MAX);
+    }
+
+    #[test]
+    fn test_zncc() {
+        let mut rng = StdRng::seed_from_u64(75);
+        let a = random_patch(&mut rng);
+        let mut inverted = a;
+        let mut scaled = a;
+        for i in 0..64 {
+            inverted[i] = 255 - a[i];
+            scaled[i] = a[i] / 2 + 20;
+        }
+
+        assert!((zncc(&a, &a) - 1.0).abs() < 1e-6);
+        assert!((zncc(&a, &inverted) + 1.0).abs() < 1e-6);
+        assert!(zncc(&a, &scaled) > 0.99);
+        assert_eq!(zncc(&a, &[90; 64]), 0.0);
+        assert_eq!(zncc(&[90; 64], &[90; 64]), 0.0);
+    }
+
+    #[test]
+    fn test_match_image_zncc() {
+        let image = planted_image(60, 50, &[(30, 25)]);
+        let reference = sample_patch(&image, 30, 25).unwrap();
+        let scores = match_image_zncc(&image, &reference);
+
+        assert!((scores.get_pixel(30, 25)[0] - 1.0).abs() < 1e-6);
+        assert!(scores.get_pixel(31, 25)[0] < 0.9);
+        assert_eq!(scores.get_pixel(12, 40)[0], 0.0);
+        assert_eq!(scores.get_pixel(0, 0)[0], -1.0);
+    }
+
+    #[test]
+    fn test_find_best_match() {
+        let image = planted_image(32, 24, &[(20, 12)]);
+        let mut model = PatchModel::new();
+        model.add_normalised_sample(sample_patch(&image, 20, 12).unwrap());
+
+        assert_eq!(find_best_match(&image, &model.quantise()), Ok((20, 12, Discrepancy(0))));
+    }
+
+    #[test]
+    fn test_find_best_match_prefers_first_in_row_major_order() {
+        let image: ImageBuffer<Luma<u8>, Vec<u8>> = ImageBuffer::new(20, 20);
+        let model = PatchModel::new().quantise();
+        assert_eq!(find_best_match(&image, &model), Ok((7, 7, Discrepancy(64))));
+    }
+
+    #[test]
+    fn test_match_templates() {
+        // The same texture, but mirrored at the second location
+        let planted = planted_image(80, 60, &[(20, 15), (60, 40)]);
+        let mut image = planted.clone();
+        for y in 33..48 {
+            for x in 53..68 {
+                image.put_pixel(x, y, *planted.get_pixel(120 - x, y));
+            }
+        }
+
+        let models = [trained_descriptor(&image, &[(20, 15)]), trained_descriptor(&image, &[(60, 40)])];
+
+        let best = match_templates(&image, &models);
+        assert_eq!(best, vec![Some((20, 15, 0)), Some((60, 40, 0))]);
+        let best_match = |image, model| find_best_match(image, model).ok().map(|(x, y, d)| (x, y, d.0));
+        assert_eq!(best[0], best_match(&image, &models[0]));
+        assert_eq!(best[1], best_match(&image, &models[1]));
+        assert_eq!(match_templates(&image, &[]), vec![]);
+
+        let blank: ImageBuffer<Luma<u8>, Vec<u8>> = ImageBuffer::new(20, 20);
+        assert_eq!(match_templates(&blank, &models[..1])[0], best_match(&blank, &models[0]));
+    }
+
+    #[test]
+    fn test_find_best_match_small_image() {
+        let image: ImageBuffer<Luma<u8>, Vec<u8>> = ImageBuffer::new(14, 14);
+        assert_eq!(find_best_match(&image, &[0u64; 5]), Err(VisionError::ImageTooSmall));
+    }
+
+    #[test]
+    fn test_entry_points_on_tiny_image() {
+        let image: ImageBuffer<Luma<u8>, Vec<u8>> = ImageBuffer::new(3, 3);
+        let model = [!0u64; 5];
+
+        assert_eq!(find_best_match(&image, &model), Err(VisionError::ImageTooSmall));
+        assert_eq!(match_templates(&image, &[model]), vec![None]);
+        assert_eq!(detect_matches(&image, &model, 320), Err(VisionError::ImageTooSmall));
+        assert_eq!(match_pyramid(&image, &model, &[1.0, 2.0]), None);
+        assert_eq!(PatchModel::new().train_from_image(&image, &[(1, 1)]), Err(VisionError::ImageTooSmall));
+
+        assert_eq!(match_image(&image, &model).into_raw(), vec![u16::MAX; 9]);
+        assert_eq!(match_image_integral(&image, &model).into_raw(), vec![u16::MAX; 9]);
+        assert_eq!(match_image_normalised(&image, &model).into_raw(), vec![0; 9]);
+    }
+
+    #[test]
+    fn test_detect_matches() {
+        let image = planted_image(40, 24, &[(10, 9), (28, 14)]);
+        let mut model = PatchModel::new();
+        model.add_normalised_sample(sample_patch(&image, 10, 9).unwrap());
+
+        let matches = detect_matches(&image, &model.quantise(), 2).unwrap();
+        assert_eq!(matches, vec![(10, 9, 0), (28, 14, 0)]);
+    }
+
+    #[test]
+    fn test_match_image_roi() {
+        let image = planted_image(40, 24, &[(10, 9), (28, 14)]);
+        let descriptor = trained_descriptor(&image, &[(10, 9)]);
+
+        let matches = match_image_roi(&image, &descriptor, (24, 9, 8, 8)).unwrap();
+        assert_eq!(matches.len(), 64);
+        assert!(matches.iter().all(|&(x, y, _)| (24..32).contains(&x) && (9..17).contains(&y)));
+        assert!(matches.contains(&(28, 14, 0)));
+        assert!(!matches.iter().any(|&(x, y, _)| (x, y) == (10, 9)));
+        for &(x, y, score) in &matches {
+            assert_eq!(Some(score), score_at(&image, x, y, &descriptor));
+        }
+
+        // Regions are clamped to the valid region rather than panicking
+        let clamped = match_image_roi(&image, &descriptor, (20, 0, 1000, 1000)).unwrap();
+        assert_eq!(clamped.len(), 13 * 10);
+        assert_eq!(clamped[0], (20, 7, clamped[0].2));
+    }
+
+    #[test]
+    fn test_match_image_roi_too_small() {
+        let image = planted_image(40, 24, &[(10, 9)]);
+        let descriptor = [0; 5];
+        // Empty or out of bounds regions contain no valid centre
+        assert_eq!(match_image_roi(&image, &descriptor, (100, 100, 5, 5)), Ok(vec![]));
+        assert_eq!(match_image_roi(&image, &descriptor, (10, 9, 0, 5)), Ok(vec![]));
+        assert_eq!(match_image_roi(&image, &descriptor, (0, 0, 7, 30)), Ok(vec![]));
+
+        let tiny: ImageBuffer<Luma<u8>, Vec<u8>> = ImageBuffer::new(14, 14);
+        assert_eq!(match_image_roi(&tiny, &descriptor, (0, 0, 14, 14)), Err(VisionError::ImageTooSmall));
+    }
+
+    #[test]
+    fn test_match_image_confident() {
+        let image = planted_image(40, 24, &[(28, 12)]);
+        let descriptor = trained_descriptor(&image, &[(28, 12), (10, 12)]);
+
+        // Both the bland and the textured region match the model
+        assert_eq!(score_at(&image, 10, 12, &descriptor), Some(0));
+        assert_eq!(score_at(&image, 28, 12, &descriptor), Some(0));
+
+        let matches = match_image_confident(&image, &descriptor, 1.0);
+        assert!(matches.contains(&(28, 12, 0)));
+        assert!(matches.iter().all(|&(x, _, _)| x > 12));
+        for &(x, y, score) in &matches {
+            assert_eq!(Some(score), score_at(&image, x, y, &descriptor));
+        }
+
+        let all = match_image_confident(&image, &descriptor, 0.0);
+        assert_eq!(all.len(), 26 * 10);
+
+        let tiny: ImageBuffer<Luma<u8>, Vec<u8>> = ImageBuffer::new(14, 30);
+        assert!(match_image_confident(&tiny, &descriptor, 1.0).is_empty());
+    }
+
+    #[test]
+    fn test_suppress_non_minima() {
+        let detections = vec![
+            (10, 10, 5), (11, 10, 3), (12, 10, 4),
+            (10, 11, 6), (11, 11, 7), (12, 12, 3),
+            (30, 30, 8)
+        ];
+        assert_eq!(suppress_non_minima(&detections, 3), vec![(11, 10, 3), (30, 30, 8)]);
+    }
+
+    #[test]
+    fn test_discrepancy_ordering() {
+        let patch = [0b01, 0, 0, 0, 0];
+        let close = score(&patch, &[0b10, 0, 0, 0, 0]);
+        let far = score(&patch, &[0b11, 0, 0, 0, 0]);
+        assert!(close < far);
+
+        let mut scores = vec![Discrepancy(7), Discrepancy(2), Discrepancy(320)];
+        scores.sort();
+        assert_eq!(scores, vec![Discrepancy(2), Discrepancy(7), Discrepancy(320)]);
+        assert_eq!(scores.iter().min(), Some(&Discrepancy(2)));
+        assert_eq!(Discrepancy(12).to_string(), "12");
+    }
+
+    #[test]
+    fn test_similarity_ordering() {
+        let model = [0b11, 0, 0, 0, 0];
+        let best = score_similarity(&[0b100, 0, 0, 0, 0], &model);
+        let worst = score_similarity(&[0b1, 0, 0, 0, 0], &model);
+        assert!(best > worst);
+        assert_eq!(best, Similarity(1.0));
+
+        let mut scores = vec![Similarity(0.5), Similarity(1.0), Similarity(0.0)];
+        scores.sort();
+        assert_eq!(scores, vec![Similarity(0.0), Similarity(0.5), Similarity(1.0)]);
+        assert_eq!(scores.iter().max(), Some(&Similarity(1.0)));
+        assert_eq!(Similarity(f32::NAN), Similarity(f32::NAN));
+        assert_eq!(format!("{:.2}", Similarity(0.75)), "0.75");
+    }
+
+    #[test]
+    fn test_similarity_of_training_patch() {
+        let mut sample = [0u8; 64];
+        for (i, s) in sample.iter_mut().enumerate() {
+            *s = (i * 4) as u8;
+        }
+        let mut model = PatchModel::new();
+        model.add_sample(sample);
+
+        assert_eq!(similarity(&quantise_patch(&sample), &model.quantise()), 1.0);
+    }
+
+    #[test]
+    fn test_similarity() {
+        let model = [0b1111, 0, 0, 0, 0];
+        assert_eq!(similarity(&[0b110000, 0, 0, 0, 0], &model), 1.0);
+        assert_eq!(similarity(&[0b0011, 0, 0, 0, 0], &model), 0.5);
+        assert_eq!(similarity(&model, &model), 0.0);
+        assert_eq!(similarity(&model, &[0u64; 5]), 1.0);
+    }
+
+    #[test]
+    fn test_scores_of_degenerate_inputs_are_finite() {
+        assert_eq!(similarity(&[0; 5], &[0; 5]), 1.0);
+        assert_eq!(similarity(&[!0; 5], &[!0; 5]), 0.0);
+
+        let flat = [17u8; 64];
+        assert_eq!(zncc(&flat, &flat), 0.0);
+        assert_eq!(zncc(&[0; 64], &[255; 64]), 0.0);
+
+        let nan = [f32::NAN; 64];
+        let mut inf = [1f32; 64];
+        inf[9] = f32::INFINITY;
+        let mut mixed = [2f32; 64];
+        mixed[0] = f32::NEG_INFINITY;
+        mixed[1] = f32::INFINITY;
+        for patch in &[nan, inf, mixed] {
+            assert_eq!(normalise_f32(patch), [0f32; 64]);
+            assert_eq!(normalise(patch), [128u8; 64]);
+        }
+
+        let mut model = PatchModel::new();
+        assert_eq!(model.try_add_normalised_sample(&nan), Err(VisionError::FlatPatch));
+        model.add_normalised_sample_f32(&inf);
+        assert_eq!(model.sample_count(), 1);
+        assert_eq!(model[9], [0, 0, 1, 0, 0]);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_descriptor_serde_round_trip() {
+        use serde_json;
+
+        let descriptor = Descriptor([1, 2, 3, !0, 1 << 63]);
+        let json = serde_json::to_string(&descriptor).unwrap();
+        let restored: Descriptor = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored, descriptor);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_patch_model_serde_round_trip() {
+        use serde_json;
+
+        let mut model = PatchModel::new();
+        for i in 0..10u8 {
+            let mut sample = [0u8; 64];
+            for (j, s) in sample.iter_mut().enumerate() {
+                *s = i.wrapping_mul(j as u8).wrapping_mul(29);
+            }
+            model.add_sample(sample);
+        }
+
+        let json = serde_json::to_string(&model).unwrap();
+        let restored: PatchModel = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.hists, model.hists);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_patch_model_deserialise_wrong_length() {
+        use serde_json;
+
+        let result = serde_json::from_str::<PatchModel>("{\"hists\":[[1,2,3,4,5]]}");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_merge() {
+        let samples = (0..10u8).map(|i| {
+            let mut sample = [0u8; 64];
+            for (j, s) in sample.iter_mut().enumerate() {
+                *s = i.wrapping_mul(j as u8).wrapping_mul(29);
+            }
+            sample
+        }).collect::<Vec<_>>();
+
+        let mut all = PatchModel::new();
+        let mut first = PatchModel::new();
+        let mut second = PatchModel::new();
+        for (i, sample) in samples.iter().enumerate() {
+            all.add_sample(sample);
+            if i < 5 {
+                first.add_sample(sample);
+            } else {
+                second.add_sample(sample);
+            }
+        }
+
+        assert_eq!(PatchModel::merged(&[first, second]).hists, all.hists);
+    }
+
+    #[test]
+    fn test_add_models() {
+        let mut rng = StdRng::seed_from_u64(98);
+        let shards = (0..4).map(|i| random_model(&mut rng, i + 1)).collect::<Vec<_>>();
+
+        let mut merged = PatchModel::new();
+        for shard in &shards {
+            merged.merge(shard);
+        }
+
+        let summed = shards.clone().into_iter().fold(PatchModel::new(), |a, b| a + b);
+        assert_eq!(summed.hists, merged.hists);
+
+        let mut assigned = PatchModel::new();
+        for shard in shards {
+            assigned += shard;
+        }
+        assert_eq!(assigned.hists, merged.hists);
+
+        let mut full = PatchModel::new();
+        full.hists[0][0] = u32::MAX;
+        let mut one = PatchModel::new();
+        one.hists[0][0] = 1;
+        assert_eq!((full + one).hists[0][0], u32::MAX);
+    }
+
+    thread_local! {
+        /// The number of times PatchModel::descriptor has quantised
+        /// a model on this thread.
+        pub static QUANTISE_CALLS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    #[test]
+    fn test_descriptor_is_cached() {
+        let mut rng = StdRng::seed_from_u64(99);
+        let mut model = random_model(&mut rng, 3);
+        let calls = || QUANTISE_CALLS.with(|calls| calls.get());
+
+        assert_eq!(model.descriptor(), model.quantise());
+        assert_eq!(model.descriptor(), model.quantise());
+        assert_eq!(calls(), 1);
+
+        model.add_sample(random_patch(&mut rng));
+        assert_eq!(model.descriptor(), model.quantise());
+        assert_eq!(calls(), 2);
+
+        let other = random_model(&mut rng, 2);
+        model.merge(&other);
+        assert_eq!(model.descriptor(), model.quantise());
+        model[5] = [0; 5];
+        assert_eq!(model.descriptor(), model.quantise());
+        model.clear();
+        assert_eq!(model.descriptor(), [!0; 5]);
+        assert_eq!(model.descriptor(), [!0; 5]);
+        assert_eq!(calls(), 5);
+
+        // Clones share the cached value without recomputing it
+        let clone = model.clone();
+        assert_eq!(clone.descriptor(), [!0; 5]);
+        assert_eq!(calls(), 5);
+    }
+
+    #[test]
+    fn test_merge_saturates() {
+        let mut model = PatchModel::new();
+        model.hists[3][1] = u32::MAX - 1;
+        let mut other = PatchModel::new();
+        other.hists[3][1] = 5;
+
+        model.merge(&other);
+        assert_eq!(model.hists[3][1], u32::MAX);
+    }
+
+    #[test]
+    fn test_quantise_words_default_size() {
+        let mut model = PatchModel::new();
+        let mut sample = [0u8; 64];
+        for (i, s) in sample.iter_mut().enumerate() {
+            *s = (i * 4) as u8;
+        }
+        model.add_sample(sample);
+        assert_eq!(PatchModel::<64>::WORDS_PER_BIN, 1);
+        assert_eq!(model.quantise_words(), model.quantise().to_vec());
+    }
+
+    #[test]
+    fn test_quantise_words_256_locations() {
+        let mut model = PatchModel::<256>::default();
+        let mut sample = [0u8; 256];
+        sample[200] = 255;
+        model.add_sample(sample);
+
+        let words = model.quantise_words();
+        assert_eq!(PatchModel::<256>::WORDS_PER_BIN, 4);
+        assert_eq!(words.len(), 20);
+        // Bin 0 is rare only at location 200, and bin 4 everywhere else.
+        assert_eq!(&words[0..4], &[0, 0, 0, 1 << 8]);
+        assert_eq!(&words[4..16], &[!0u64; 12][..]);
+        assert_eq!(&words[16..20], &[!0, !0, !0, !0 ^ (1 << 8)]);
+    }
+
+    #[test]
+    fn test_quantise_with_threshold() {
+        let mut model = PatchModel::new();
+        for h in 0..64 {
+            model.hists[h] = [1, 9, 90, 0, 0];
+        }
+
+        assert_eq!(model.quantise_with_threshold(0.05), Ok([!0, 0, 0, !0, !0]));
+        assert_eq!(model.quantise_with_threshold(0.1), Ok([!0, !0, 0, !0, !0]));
+        assert_eq!(model.quantise_with_threshold(0.05), Ok(model.quantise()));
+    }
+
+    struct BelowCount(u32);
+
+    impl RarityRule for BelowCount {
+        fn is_rare(&self, bin_count: u32, _total: u64) -> bool {
+            bin_count < self.0
+        }
+    }
+
+    #[test]
+    fn test_quantise_with_rule() {
+        let mut model = PatchModel::new();
+        for h in 0..64 {
+            model.hists[h] = [1, 9, 90, 0, 0];
+        }
+        model.hists[3] = [0; 5];
+
+        assert_eq!(model.quantise_with_rule(&FractionRule(0.05)), model.quantise());
+        let descriptor = model.quantise_with_rule(&BelowCount(10));
+        assert_eq!(descriptor, [!0, !0, 1 << 3, !0, !0]);
+        assert_ne!(descriptor, model.quantise());
+        assert_eq!(model.quantise_with_rule(&BelowCount(0)), [1 << 3; 5]);
+    }
+
+    #[test]
+    fn test_quantise_with_invalid_threshold() {
+        let model = PatchModel::new();
+        assert_eq!(model.quantise_with_threshold(-0.1), Err(VisionError::InvalidThreshold(-0.1)));
+        assert_eq!(model.quantise_with_threshold(1.5), Err(VisionError::InvalidThreshold(1.5)));
+        assert!(model.quantise_with_threshold(f32::NAN).is_err());
+        assert!(model.quantise_with_threshold(1.0).is_ok());
+    }
+
+    #[test]
+    fn test_train_from_image() {
+        let image = planted_image(32, 24, &[(20, 12)]);
+        let mut model = PatchModel::new();
+        let count = model.train_from_image(&image, &[(20, 12), (3, 12), (20, 20), (10, 10)]);
+        assert_eq!(count, Ok(2));
+
+        let mut expected = PatchModel::new();
+        expected.add_normalised_sample(sample_patch(&image, 20, 12).unwrap());
+        expected.add_normalised_sample(sample_patch(&image, 10, 10).unwrap());
+        assert_eq!(model.hists, expected.hists);
+    }
+
+    #[test]
+    fn test_descriptor_to_image() {
+        let image = descriptor_to_image(&[1, 0, 1 << 9, 0, 1 << 63]);
+        assert_eq!(image.dimensions(), (40, 8));
+        assert_eq!(image.get_pixel(0, 0)[0], 255);
+        assert_eq!(image.get_pixel(1, 0)[0], 0);
+        assert_eq!(image.get_pixel(8, 0)[0], 0);
+        assert_eq!(image.get_pixel(17, 1)[0], 255);
+        assert_eq!(image.get_pixel(39, 7)[0], 255);
+        assert_eq!(image.pixels().filter(|p| p[0] == 255).count(), 3);
+    }
+
+    #[test]
+    fn test_descriptor_to_features() {
+        let features = descriptor_to_features(&[1, 0, 1 << 9 | 1 << 10, 0, 1 << 63]);
+        let ones = (0..320).filter(|&i| features[i] == 1.0).collect::<Vec<_>>();
+        assert_eq!(ones, vec![0, 137, 138, 319]);
+        assert!(features.iter().all(|&f| f == 0.0 || f == 1.0));
+    }
+
+    #[test]
+    fn test_empty_bins() {
+        assert_eq!(empty_bins(&[1, !0, 0, 1 << 63, 5]), vec![2]);
+        assert_eq!(empty_bins(&[0; 5]), vec![0, 1, 2, 3, 4]);
+        assert!(empty_bins(&[1; 5]).is_empty());
+    }
+
+    #[test]
+    fn test_hamming() {
+        let a = [0b1011, 0, 1 << 63, !0, 7];
+        let b = [0b0110, !0, 1 << 63, 0, 7];
+        assert_eq!(hamming(&a, &a), 0);
+        assert_eq!(hamming(&[0; 5], &[!0; 5]), 320);
+        assert_eq!(hamming(&a, &b), 131);
+        assert_eq!(hamming(&b, &a), 131);
+    }
+
+    #[test]
+    fn test_descriptor_bytes_round_trip() {
+        let d = [0x0123456789abcdef, 0, !0, 1 << 63, 42];
+        assert_eq!(descriptor_from_bytes(&descriptor_to_bytes(&d)), d);
+    }
 
     #[test]
-    fn test_bin() {
-        assert_eq!(bin(0), 0);
-        assert_eq!(bin(51), 0);
-        assert_eq!(bin(52), 1);
-        assert_eq!(bin(255), 4);
+    fn test_descriptor_to_bytes_layout() {
+        let bytes = descriptor_to_bytes(&[0x0102030405060708, 0, 0, 0, 0xff]);
+        assert_eq!(&bytes[0..8], &[8, 7, 6, 5, 4, 3, 2, 1]);
+        assert_eq!(&bytes[8..32], &[0u8; 24][..]);
+        assert_eq!(&bytes[32..40], &[0xff, 0, 0, 0, 0, 0, 0, 0]);
     }
 
     #[test]
-    fn test_set_bit() {
-        assert_eq!(set_bit(0, 0), 1);
-        assert_eq!(set_bit(0, 1), 2);
-        assert_eq!(set_bit(0, 2), 4);
-        assert_eq!(set_bit(1, 1), 3);
+    fn test_descriptor_debug() {
+        let descriptor = Descriptor([1, 0xff, 0, !0, 1 << 63]);
+        assert_eq!(format!("{:?}", descriptor),
+            "Descriptor([0x0000000000000001, 0x00000000000000ff, 0x0000000000000000, \
+             0xffffffffffffffff, 0x8000000000000000])");
+    }
+
+    #[test]
+    fn test_cloned_model_quantises_identically() {
+        let image = planted_image(32, 24, &[(20, 12)]);
+        let mut model = PatchModel::new();
+        model.train_from_image(&image, &[(20, 12), (10, 10), (15, 9)]).unwrap();
+
+        let snapshot = model.clone();
+        assert_eq!(Descriptor(snapshot.quantise()), Descriptor(model.quantise()));
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_train_from_image_parallel() {
+        let image = planted_image(64, 48, &[(20, 12), (40, 30)]);
+        let mut keypoints = Vec::new();
+        for y in 0..48 {
+            for x in 0..64 {
+                keypoints.push((x, y));
+            }
+        }
+
+        let mut serial = PatchModel::new();
+        let serial_count = serial.train_from_image(&image, &keypoints);
+        let mut parallel = PatchModel::new();
+        let parallel_count = parallel.train_from_image_parallel(&image, &keypoints);
+
+        assert_eq!(parallel_count, serial_count);
+        assert_eq!(parallel.hists, serial.hists);
+        assert_eq!(parallel.quantise(), serial.quantise());
+
+        let tiny: ImageBuffer<Luma<u8>, Vec<u8>> = ImageBuffer::new(3, 3);
+        assert_eq!(parallel.train_from_image_parallel(&tiny, &[(1, 1)]), Err(VisionError::ImageTooSmall));
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_match_image_parallel() {
+        use std::time::Instant;
+        use super::match_image_parallel;
+
+        let image = planted_image(160, 120, &[(20, 12), (90, 70), (140, 100)]);
+        let mut model = PatchModel::new();
+        model.add_normalised_sample(sample_patch(&image, 20, 12).unwrap());
+        let descriptor = model.quantise();
+
+        let start = Instant::now();
+        let serial = match_image(&image, &descriptor);
+        let serial_time = start.elapsed();
+        let start = Instant::now();
+        let parallel = match_image_parallel(&image, &descriptor);
+        let parallel_time = start.elapsed();
+
+        println!("match_image: {:?}, match_image_parallel: {:?}", serial_time, parallel_time);
+        assert_eq!(parallel.into_raw(), serial.into_raw());
+
+        let tiny: ImageBuffer<Luma<u8>, Vec<u8>> = ImageBuffer::new(3, 3);
+        assert_eq!(match_image_parallel(&tiny, &descriptor).into_raw(), vec![u16::MAX; 9]);
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_detect_matches_parallel() {
+        use super::detect_matches_parallel;
+
+        let image = planted_image(120, 90, &[(20, 12), (60, 45), (100, 70)]);
+        let mut model = PatchModel::new();
+        model.add_normalised_sample(sample_patch(&image, 20, 12).unwrap());
+        let descriptor = model.quantise();
+
+        let serial = detect_matches(&image, &descriptor, 40).unwrap();
+        assert!(serial.len() >= 3);
+        assert_eq!(detect_matches_parallel(&image, &descriptor, 40), Ok(serial));
+
+        let tiny: ImageBuffer<Luma<u8>, Vec<u8>> = ImageBuffer::new(3, 3);
+        assert_eq!(detect_matches_parallel(&tiny, &descriptor, 40), Err(VisionError::ImageTooSmall));
+    }
+
+    /// A row-major buffer of pixels with a row stride which may
+    /// exceed its width.
+    struct StridedBuffer {
+        pixels: Vec<u8>,
+        width: u32,
+        height: u32,
+        row_stride: usize
+    }
+
+    impl PatchSource for StridedBuffer {
+        fn get_luma(&self, x: u32, y: u32) -> Option<u8> {
+            if x < self.width && y < self.height {
+                Some(self.pixels[y as usize * self.row_stride + x as usize])
+            } else {
+                None
+            }
+        }
+
+        fn dimensions(&self) -> (u32, u32) {
+            (self.width, self.height)
+        }
+    }
+
+    #[test]
+    fn test_sample_patch_custom_source() {
+        let image = planted_image(30, 25, &[(12, 10)]);
+        let row_stride = 32;
+        let mut pixels = vec![0u8; row_stride * 25];
+        for (x, y, p) in image.enumerate_pixels() {
+            pixels[y as usize * row_stride + x as usize] = p[0];
+        }
+        let source = StridedBuffer { pixels, width: 30, height: 25, row_stride };
+
+        assert_eq!(sample_patch(&source, 12, 10), sample_patch(&image, 12, 10));
+        assert_eq!(sample_patch(&source, 20, 15), sample_patch(&image, 20, 15));
+        assert_eq!(sample_patch::<_, u8>(&source, 23, 10), None);
+    }
+
+    #[test]
+    fn test_sample_patch_sub_image() {
+        let image: ImageBuffer<Luma<u8>, Vec<u8>> =
+            ImageBuffer::from_fn(32, 32, |x, y| Luma([(x + 8 * y) as u8]));
+        let view = image.view(5, 3, 16, 16);
+
+        assert_eq!(sample_patch(&*view, 7, 7), sample_patch(&image, 12, 10));
+        assert!(sample_patch(&*view, 9, 7).is_none());
+    }
+
+    #[test]
+    fn test_sample_and_bin_16_bit() {
+        let image: ImageBuffer<Luma<u16>, Vec<u16>> =
+            ImageBuffer::from_fn(16, 16, |x, _| Luma([(x * 4369) as u16]));
+
+        let sample = sample_patch(&image, 7, 7).unwrap();
+        assert_eq!(sample[0], 0);
+        assert_eq!(sample[7], 61166);
+        assert_eq!(bin(sample[0], 5), 0);
+        assert_eq!(bin(sample[7], 5), 4);
+        assert_eq!(bin(65535u16, 5), 4);
+        assert_eq!(bin(13107u16, 5), 0);
+        assert_eq!(bin(13108u16, 5), 1);
+    }
+
+    #[test]
+    fn test_sample_patch_rgb() {
+        let image: ImageBuffer<Rgb<u8>, Vec<u8>> =
+            ImageBuffer::from_pixel(16, 16, Rgb([200, 100, 50]));
+        assert_eq!(&sample_patch_rgb(&image, 8, 8).unwrap()[..], &[124u8; 64][..]);
+        assert!(sample_patch_rgb(&image, 6, 8).is_none());
+
+        let white: ImageBuffer<Rgb<u8>, Vec<u8>> =
+            ImageBuffer::from_pixel(16, 16, Rgb([255, 255, 255]));
+        assert_eq!(&sample_patch_rgb(&white, 8, 8).unwrap()[..], &[255u8; 64][..]);
+    }
+
+    #[test]
+    fn test_sample_patch_subpixel_at_integer_point() {
+        let image = planted_image(32, 24, &[(15, 12)]);
+        assert_eq!(sample_patch_subpixel(&image, 15.0, 12.0), sample_patch(&image, 15, 12));
+    }
+
+    #[test]
+    fn test_sample_patch_subpixel_at_half_pixel() {
+        let image: ImageBuffer<Luma<u8>, Vec<u8>> =
+            ImageBuffer::from_fn(32, 32, |x, y| Luma([(x * y) as u8]));
+
+        let sample = sample_patch_subpixel(&image, 10.5, 10.5).unwrap();
+        // (3 * 3 + 4 * 3 + 3 * 4 + 4 * 4) / 4 = 12.25
+        assert_eq!(sample[0], 12);
+        // (5 * 5 + 6 * 5 + 5 * 6 + 6 * 6) / 4 = 30.25
+        assert_eq!(sample[9], 30);
+        // (15 * 3 + 16 * 3 + 15 * 4 + 16 * 4) / 4 = 54.25
+        assert_eq!(sample[6], 54);
+    }
+
+    #[test]
+    fn test_sample_patch_subpixel_near_boundary() {
+        let image: ImageBuffer<Luma<u8>, Vec<u8>> = ImageBuffer::new(16, 16);
+        assert!(sample_patch_subpixel(&image, 6.9, 7.5).is_none());
+        assert!(sample_patch_subpixel(&image, 7.5, 7.5).is_some());
+        assert!(sample_patch_subpixel(&image, 8.0, 7.5).is_some());
+        assert!(sample_patch_subpixel(&image, 8.1, 7.5).is_none());
+        assert!(sample_patch_subpixel(&image, 7.5, 8.1).is_none());
+    }
+
+    #[test]
+    fn test_sample_patch_subpixel_at_last_valid_centre() {
+        let image = planted_image(32, 24, &[(24, 16)]);
+        assert_eq!(sample_patch_subpixel(&image, 24.0, 16.0), sample_patch(&image, 24, 16));
+        assert!(sample_patch(&image, 24, 16).is_some());
+        assert!(sample_patch_subpixel(&image, 24.0, 16.5).is_none());
+    }
+
+    #[test]
+    fn test_add_sample_rotations() {
+        use std::f32::consts::FRAC_PI_2;
+
+        // Intensity varies only with x in vertical, and only with y in
+        // horizontal, so each is the other rotated by 90 degrees
+        let vertical = ImageBuffer::from_fn(41, 41, |x, _| Luma([(16 * (x as i32 - 20).abs()) as u8]));
+        let horizontal = ImageBuffer::from_fn(41, 41, |_, y| Luma([(16 * (y as i32 - 20).abs()) as u8]));
+        let vertical_patch = quantise_patch(&normalise(&sample_patch(&vertical, 20, 20).unwrap()));
+        let horizontal_patch = quantise_patch(&normalise(&sample_patch(&horizontal, 20, 20).unwrap()));
+
+        let mut unrotated = PatchModel::new();
+        assert_eq!(unrotated.add_sample_rotations(&vertical, 20, 20, &[0.0]), 1);
+        let unrotated = unrotated.quantise();
+
+        let mut rotated = PatchModel::new();
+        assert_eq!(rotated.add_sample_rotations(&vertical, 20, 20, &[0.0, FRAC_PI_2]), 2);
+        let rotated = rotated.quantise();
+
+        assert_eq!(discrepancy(&vertical_patch, &rotated), 0);
+        assert_eq!(discrepancy(&horizontal_patch, &rotated), 0);
+        assert!(discrepancy(&horizontal_patch, &unrotated) > 0);
+
+        // The corners of a rotated grid reach further than 7 pixels
+        assert_eq!(PatchModel::new().add_sample_rotations(&vertical, 8, 20, &[FRAC_PI_2 / 2.0]), 0);
+    }
+
+    #[test]
+    fn test_sample_patch_strided() {
+        let image: ImageBuffer<Luma<u8>, Vec<u8>> =
+            ImageBuffer::from_fn(32, 32, |x, y| Luma([(x + 8 * y) as u8]));
+
+        let sample = sample_patch_strided(&image, 15, 14, 2).unwrap();
+        for j in 0..8 {
+            for i in 0..8 {
+                let (x, y) = (1 + 4 * i, 4 * j);
+                assert_eq!(sample[8 * j + i], (x + 8 * y) as u8);
+            }
+        }
+
+        assert_eq!(sample_patch_strided(&image, 9, 9, 1), sample_patch(&image, 9, 9));
+        assert!(sample_patch_strided(&image, 13, 14, 2).is_none());
+        assert!(sample_patch_strided(&image, 15, 13, 2).is_none());
+        assert!(sample_patch_strided(&image, 18, 14, 2).is_none());
+        assert!(sample_patch_strided(&image, 17, 17, 2).is_some());
+    }
+
+    #[test]
+    fn test_sample_patch_aniso() {
+        let image: ImageBuffer<Luma<u8>, Vec<u8>> =
+            ImageBuffer::from_fn(32, 20, |x, y| Luma([(x + 8 * y) as u8]));
+
+        let sample = sample_patch_aniso(&image, 15, 9, 2, 1).unwrap();
+        for j in 0..8 {
+            for i in 0..8 {
+                let (x, y) = (1 + 4 * i, 2 + 2 * j);
+                assert_eq!(sample[8 * j + i], (x + 8 * y) as u8);
+            }
+        }
+
+        assert_eq!(sample_patch_aniso(&image, 9, 9, 1, 1), sample_patch(&image, 9, 9));
+
+        // The horizontal footprint is twice as wide, the vertical unchanged
+        assert!(sample_patch_aniso(&image, 13, 9, 2, 1).is_none());
+        assert!(sample_patch_aniso(&image, 18, 9, 2, 1).is_none());
+        assert!(sample_patch_aniso(&image, 14, 7, 2, 1).is_some());
+        assert!(sample_patch_aniso(&image, 14, 12, 2, 1).is_some());
+        assert!(sample_patch_aniso(&image, 14, 6, 2, 1).is_none());
+        assert!(sample_patch_aniso(&image, 14, 13, 2, 1).is_none());
+    }
+
+    #[test]
+    fn test_grid_keypoints() {
+        let keypoints = grid_keypoints(40, 30, 5);
+        assert_eq!(keypoints.len(), 6 * 4);
+        assert_eq!(keypoints[0], (7, 7));
+        assert_eq!(keypoints[23], (32, 22));
+        let (x_min, y_min, x_max, y_max) = valid_region(40, 30).unwrap();
+        assert!(keypoints.iter().all(|&(x, y)| x >= x_min && x <= x_max && y >= y_min && y <= y_max));
+
+        let image: ImageBuffer<Luma<u8>, Vec<u8>> = ImageBuffer::new(40, 30);
+        let mut model = PatchModel::new();
+        assert_eq!(model.train_from_image(&image, &keypoints), Ok(24));
+
+        assert_eq!(grid_keypoints(15, 15, 3), vec![(7, 7)]);
+        assert!(grid_keypoints(14, 40, 1).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot bin into zero bins")]
+    fn test_bin_f32_zero_bins() {
+        bin_f32(0.5, 0);
+    }
+
+    #[test]
+    fn test_reflect() {
+        let reflected = (-2..6).map(|c| reflect(c, 4)).collect::<Vec<_>>();
+        assert_eq!(reflected, vec![2, 1, 0, 1, 2, 3, 2, 1]);
+        assert_eq!(reflect(-5, 1), 0);
+    }
+
+    #[test]
+    fn test_sample_patch_reflect() {
+        let image: ImageBuffer<Luma<u8>, Vec<u8>> =
+            ImageBuffer::from_fn(16, 16, |x, y| Luma([(x + 16 * y) as u8]));
+
+        let sample = sample_patch_reflect(&image, 0, 0);
+        // Offsets -7, -5, -3, -1 reflect to 7, 5, 3, 1.
+        let coords = [7, 5, 3, 1, 1, 3, 5, 7];
+        for j in 0..8 {
+            for i in 0..8 {
+                assert_eq!(sample[8 * j + i], (coords[i] + 16 * coords[j]) as u8);
+            }
+        }
+
+        assert_eq!(sample_patch_reflect(&image, 8, 8), sample_patch(&image, 8, 8).unwrap());
+    }
+
+    #[test]
+    fn test_match_pyramid() {
+        let image = planted_image(32, 24, &[(14, 11)]);
+        let mut model = PatchModel::new();
+        model.add_normalised_sample(sample_patch(&image, 14, 11).unwrap());
+        let model = model.quantise();
+
+        // Nearest-neighbour enlargement of image to twice its size.
+        let enlarged: ImageBuffer<Luma<u8>, Vec<u8>> =
+            ImageBuffer::from_fn(64, 48, |x, y| *image.get_pixel(x / 2, y / 2));
+
+        let (x, y, scale, _) = match_pyramid(&enlarged, &model, &[1.0, 0.5]).unwrap();
+        assert_eq!(scale, 0.5);
+        assert!((x as i32 - 28).abs() <= 2 && (y as i32 - 22).abs() <= 2);
+
+        assert_eq!(match_pyramid(&image, &model, &[1.0]), Some((14, 11, 1.0, 0)));
+        assert_eq!(match_pyramid(&image, &model, &[0.1, 0.0]), None);
+        assert_eq!(match_pyramid(&image, &model, &[f32::INFINITY, f32::MAX, 1e6]), None);
+        assert_eq!(match_pyramid(&image, &model, &[f32::INFINITY, 1.0]), Some((14, 11, 1.0, 0)));
+    }
+
+    #[test]
+    fn test_location_counts() {
+        let mut model = PatchModel::new();
+        model.add_sample([0u8; 64]);
+        model.add_sample([100u8; 64]);
+        model.hists[5] = [1, 0, 0, 0, 0];
+        let counts = model.location_counts();
+        assert_eq!(counts[0], 2);
+        assert_eq!(counts[5], 1);
+    }
+
+    #[test]
+    fn test_weighted_discrepancy() {
+        let mut model = PatchModel::new();
+        for h in 0..64 {
+            model.hists[h] = [10, 0, 0, 0, 0];
+        }
+        // Location 0 has only seen two samples.
+        model.hists[0] = [2, 0, 0, 0, 0];
+
+        // A patch lying in bin 1 at locations 0 and 1, and bin 0 elsewhere.
+        let patch = [!0 ^ 3, 3, 0, 0, 0];
+        let descriptor = model.quantise();
+        assert_eq!(discrepancy(&patch, &descriptor), 2);
+        assert_eq!(weighted_discrepancy(&patch, &model, 10), 1.2);
+        assert_eq!(weighted_discrepancy(&patch, &model, 2), 2.0);
+        assert_eq!(weighted_discrepancy(&patch, &model, 0), 2.0);
+    }
+
+    #[test]
+    fn test_mean_and_stddev() {
+        assert_eq!(mean_and_stddev(&[2u8, 4, 4, 4, 5, 5, 7, 9]), (5.0, 2.0));
+        assert_eq!(mean_and_stddev(&[7u8; 64]), (7.0, 0.0));
+        assert_eq!(mean_and_stddev::<u8>(&[]), (0.0, 0.0));
+
+        let mut patch = [0u8; 64];
+        for p in &mut patch[32..] {
+            *p = 200;
+        }
+        assert_eq!(mean_and_stddev(&patch), (100.0, 100.0));
+    }
+
+    #[test]
+    fn test_sample_count() {
+        let mut model = PatchModel::new();
+        assert_eq!(model.sample_count(), 0);
+        assert!(model.is_trained(0));
+        assert!(!model.is_trained(1));
+
+        for i in 0..7u8 {
+            model.add_sample([i * 30; 64]);
+        }
+        assert_eq!(model.sample_count(), 7);
+        assert!(model.is_trained(7));
+        assert!(!model.is_trained(8));
+
+        model.hists[10] = [1, 1, 0, 0, 0];
+        assert_eq!(model.sample_count(), 2);
+        assert!(!model.is_trained(7));
+    }
+
+    #[test]
+    fn test_histograms() {
+        let mut model = PatchModel::new();
+        let mut sample = [0u8; 64];
+        sample[12] = 255;
+        model.add_sample(sample);
+        model.add_sample([60u8; 64]);
+
+        assert_eq!(model.histograms().count(), 64);
+        assert_eq!(model.histograms().nth(12), Some(&[0, 1, 0, 0, 1]));
+        assert_eq!(model.histogram(12), Some(&[0, 1, 0, 0, 1]));
+        assert_eq!(model.histogram(0), Some(&[1, 1, 0, 0, 0]));
+        assert_eq!(model.histogram(64), None);
+    }
+
+    #[test]
+    fn test_descriptor_and_or() {
+        let a = [0b1100, !0, 0, 1 << 63, 7];
+        let b = [0b1010, 0, 0, 1 << 63, 12];
+        assert_eq!(descriptor_and(&a, &b), [0b1000, 0, 0, 1 << 63, 4]);
+        assert_eq!(descriptor_or(&a, &b), [0b1110, !0, 0, 1 << 63, 15]);
+        assert_eq!(descriptor_and(&a, &a), a);
+        assert_eq!(descriptor_or(&a, &[0; 5]), a);
+    }
+
+    #[test]
+    fn test_popcount() {
+        assert_eq!(popcount(&[0; 5]), 0);
+        assert_eq!(popcount(&[!0; 5]), 320);
+        assert_eq!(popcount(&[0b1011, 0, 1 << 63, 0, 0]), 4);
+    }
+
+    #[test]
+    fn test_max_discrepancy() {
+        let mut rng = StdRng::seed_from_u64(70);
+        for _ in 0..1000 {
+            let model: PatchDescriptor = rng.gen();
+            let patch: PatchDescriptor = rng.gen();
+            assert_eq!(max_discrepancy(&model), popcount(&model));
+            assert!(discrepancy(&patch, &model) <= max_discrepancy(&model));
+            assert_eq!(discrepancy(&[!0; 5], &model), max_discrepancy(&model));
+        }
+    }
+
+    #[test]
+    fn test_rare_density() {
+        assert_eq!(rare_density(&[0; 5]), 0.0);
+        assert_eq!(rare_density(&[!0; 5]), 1.0);
+        assert_eq!(rare_density(&[!0, 0, 0, 0, 0]), 0.2);
+    }
+
+    #[test]
+    fn test_train_from_no_keypoints() {
+        let image = planted_image(32, 24, &[(20, 12)]);
+        let mut model = PatchModel::new();
+        assert_eq!(model.train_from_image(&image, &[]), Err(VisionError::NoKeypoints));
+    }
+
+    #[test]
+    fn test_diff() {
+        let mut rng = StdRng::seed_from_u64(80);
+        let a = random_model(&mut rng, 10);
+        let mut b = a.clone();
+        assert!(a.diff(&b).is_empty());
+
+        b[17][3] += 1;
+        assert_eq!(a.diff(&b), vec![(17, a[17], b[17])]);
+        assert_eq!(b.diff(&a), vec![(17, b[17], a[17])]);
+    }
+
+    #[test]
+    fn test_mean_entropy() {
+        let mut rng = StdRng::seed_from_u64(77);
+        let flat = PatchModel::from_raw_samples(vec![[40u8; 64]; 10]);
+        let varied = random_model(&mut rng, 50);
+        assert_eq!(flat.mean_entropy(), 0.0);
+        assert!(varied.mean_entropy() > 1.0);
+        assert!(varied.mean_entropy() <= 5f32.log2());
+
+        // Two equally likely bins at every location give one bit
+        let two = PatchModel::from_samples(vec![[0u8; 64], [255u8; 64]]);
+        assert!((two.mean_entropy() - 1.0).abs() < 1e-6);
+        assert_eq!(PatchModel::new().mean_entropy(), 0.0);
+    }
+
+    #[test]
+    fn test_descriptor_stability() {
+        let mut model = PatchModel::new();
+        for _ in 0..19 {
+            model.add_sample([0; 64]);
+        }
+        // Location 0 has exactly 5% of its samples in the top bin
+        let mut sample = [0u8; 64];
+        sample[0] = 255;
+        model.add_sample(sample);
+
+        assert_eq!(model.descriptor_stability(0.05, 0.0), 1);
+        assert_eq!(model.descriptor_stability(0.05, 0.01), 1);
+        assert_eq!(model.descriptor_stability(0.2, 0.01), 0);
+        assert_eq!(PatchModel::new().descriptor_stability(0.05, 1.0), 0);
+    }
+
+    #[test]
+    fn test_quantise_with_min_samples() {
+        let mut model = PatchModel::new();
+        for _ in 0..10 {
+            model.add_sample([0; 64]);
+        }
+        // Location 5 has only a few samples, all in bin 4
+        model.hists[5] = [0, 0, 0, 0, 3];
+
+        let all = model.quantise_with(0.05, 0).unwrap();
+        assert_eq!(all, model.quantise_with_threshold(0.05).unwrap());
+        assert_eq!(all[0] >> 5 & 1, 1);
+        assert_eq!(all[4] >> 5 & 1, 0);
+
+        let guarded = model.quantise_with(0.05, 5).unwrap();
+        for i in 0..5 {
+            assert_eq!(guarded[i] >> 5 & 1, 0);
+            assert_eq!(guarded[i] & !(1 << 5), all[i] & !(1 << 5));
+        }
+
+        assert_eq!(PatchModel::new().quantise_with(0.05, 1), Ok([0; 5]));
+        assert_eq!(model.quantise_with(-0.1, 5), Err(VisionError::InvalidThreshold(-0.1)));
+    }
+
+    #[test]
+    fn test_index() {
+        let mut model = PatchModel::new();
+        model.add_sample([0; 64]);
+        assert_eq!(model[10], [1, 0, 0, 0, 0]);
+
+        model[10] = [0, 0, 0, 0, 1];
+        model[11][2] += 1;
+        assert_eq!(model[10], [0, 0, 0, 0, 1]);
+        assert_eq!(model[11], [1, 0, 1, 0, 0]);
+
+        let descriptor = model.quantise();
+        assert_eq!(descriptor[0] >> 10 & 1, 1);
+        assert_eq!(descriptor[4] >> 10 & 1, 0);
+        assert_eq!(descriptor[2] >> 11 & 1, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "index out of bounds")]
+    fn test_index_out_of_range() {
+        let model = PatchModel::new();
+        let _ = model[64];
+    }
+
+    #[test]
+    fn test_location_rarity() {
+        let mut model = PatchModel::new();
+        // Location 3 receives 1, 0, 4, 10 and 5 samples in bins 0 to 4
+        let intensities = [(10, 1), (110, 4), (170, 10), (230, 5)];
+        for &(value, count) in &intensities {
+            let mut sample = [0u8; 64];
+            sample[3] = value;
+            for _ in 0..count {
+                model.add_sample(sample);
+            }
+        }
+
+        assert_eq!(model.location_rarity(3, 0.05), Some(0b00010));
+        assert_eq!(model.location_rarity(3, 0.25), Some(0b00111));
+        assert_eq!(model.location_rarity(3, 0.0), Some(0));
+        assert_eq!(model.location_rarity(0, 0.05), Some(0b11110));
+        assert_eq!(PatchModel::new().location_rarity(0, 0.05), Some(0b11111));
+        assert_eq!(model.location_rarity(64, 0.05), None);
+
+        let descriptor = model.quantise();
+        for (i, word) in descriptor.iter().enumerate() {
+            assert_eq!(word >> 3 & 1 == 1, model.location_rarity(3, 0.05).unwrap() >> i & 1 == 1);
+        }
+    }
+
+    #[test]
+    fn test_from_samples() {
+        let mut rng = StdRng::seed_from_u64(73);
+        let patches = (0..10).map(|_| random_patch(&mut rng)).collect::<Vec<_>>();
+
+        let mut expected = PatchModel::new();
+        for p in &patches {
+            expected.add_sample(p);
+        }
+        let model = PatchModel::from_samples(patches.clone());
+        assert_eq!(model.hists, expected.hists);
+
+        let mut expected = PatchModel::new();
+        for p in &patches {
+            expected.add_normalised_sample(p);
+        }
+        let model = PatchModel::from_raw_samples(patches);
+        assert_eq!(model.hists, expected.hists);
+
+        assert_eq!(PatchModel::from_samples(vec![]).sample_count(), 0);
+    }
+
+    #[test]
+    fn test_train_stream() {
+        use std::sync::mpsc::channel;
+        use std::thread;
+
+        let (tx, rx) = channel();
+        let producer = thread::spawn(move || {
+            let mut rng = StdRng::seed_from_u64(82);
+            let patches = (0..25).map(|_| random_patch(&mut rng)).collect::<Vec<_>>();
+            for p in &patches {
+                tx.send(*p).unwrap();
+            }
+            patches
+        });
+
+        let mut model = PatchModel::new();
+        assert_eq!(model.train_stream(rx), 25);
+        assert_eq!(model.sample_count(), 25);
+
+        let patches = producer.join().unwrap();
+        assert_eq!(model.hists, PatchModel::from_raw_samples(patches).hists);
+    }
+
+    #[test]
+    fn test_point_hist_ext() {
+        let hist: PointHist = [3, 9, 1, 9, 0];
+        assert_eq!(hist.total(), 22);
+        assert_eq!(hist.mode_bin(), 1);
+        assert!(!hist.is_empty());
+
+        let empty: PointHist = [0; 5];
+        assert_eq!(empty.total(), 0);
+        assert_eq!(empty.mode_bin(), 0);
+        assert!(empty.is_empty());
+
+        let full: PointHist<3> = [u32::MAX, 0, u32::MAX];
+        assert_eq!(full.total(), u32::MAX);
+        assert_eq!(full.mode_bin(), 0);
+    }
+
+    #[test]
+    fn test_add_sample_saturates() {
+        let mut model = PatchModel::new();
+        model[0] = [u32::MAX - 1, u32::MAX - 1, 0, 0, 0];
+        assert!(!model.is_saturated());
+
+        model.add_sample([0; 64]);
+        assert!(model.is_saturated());
+        assert_eq!(model[0], [u32::MAX, u32::MAX - 1, 0, 0, 0]);
+        model.add_sample([0; 64]);
+        model.add_sample_weighted([0; 64], 100);
+        model.add_sample_f32(&[-10.0; 64]);
+        assert_eq!(model[0], [u32::MAX, u32::MAX - 1, 0, 0, 0]);
+
+        // Totals no longer fit in a u32, but quantisation still works
+        assert_eq!(model.location_counts()[0], u32::MAX);
+        assert_eq!(model.location_rarity(0, 0.05), Some(0b11100));
+
+        // Fractions use the exact total rather than the saturated one,
+        // so bin 2 holds a ninth of the samples rather than a quarter
+        model[1] = [u32::MAX, u32::MAX, u32::MAX / 4, 0, 0];
+        assert_eq!(model.location_rarity(1, 0.2), Some(0b11100));
+    }
+
+    #[test]
+    fn test_clear() {
+        let image = planted_image(40, 40, &[(20, 20)]);
+        let mut model = PatchModel::new();
+        model.train_from_image(&image, &[(20, 20), (19, 20), (21, 21)]).unwrap();
+        assert_eq!(model.sample_count(), 3);
+
+        model.clear();
+        assert_eq!(model.sample_count(), 0);
+        assert_eq!(model.quantise(), PatchModel::new().quantise());
+    }
+
+    #[test]
+    fn test_add_sample_weighted() {
+        let mut sample = [0u8; 64];
+        for (i, s) in sample.iter_mut().enumerate() {
+            *s = (i * 4) as u8;
+        }
+        let other = [200u8; 64];
+
+        let mut weighted = PatchModel::new();
+        weighted.add_sample_weighted(sample, 3);
+        weighted.add_sample(other);
+
+        let mut repeated = PatchModel::new();
+        for _ in 0..3 {
+            repeated.add_sample(sample);
+        }
+        repeated.add_sample(other);
+
+        assert!(weighted.histograms().eq(repeated.histograms()));
+        assert_eq!(weighted.quantise(), repeated.quantise());
+    }
+
+    #[test]
+    fn test_add_sample_slice() {
+        let pixels = (0..64).map(|i| (4 * i) as u8).collect::<Vec<u8>>();
+        let mut model = PatchModel::new();
+        assert_eq!(model.add_sample_slice(&pixels), Ok(()));
+
+        let mut expected = PatchModel::new();
+        let mut fixed = [0u8; 64];
+        fixed.copy_from_slice(&pixels);
+        expected.add_sample(fixed);
+        assert_eq!(model.location_counts(), expected.location_counts());
+        assert_eq!(model.quantise(), expected.quantise());
+    }
+
+    #[test]
+    fn test_add_sample_slice_wrong_length() {
+        let mut model = PatchModel::new();
+        assert_eq!(model.add_sample_slice(&[0; 63]),
+            Err(VisionError::WrongLength { expected: 64, actual: 63 }));
+        assert_eq!(model.add_sample_slice(&[]),
+            Err(VisionError::WrongLength { expected: 64, actual: 0 }));
+        assert_eq!(model.sample_count(), 0);
+    }
+
+    #[test]
+    fn test_add_image_patch() {
+        let image = planted_image(40, 24, &[(10, 9)]);
+        let mut model = PatchModel::new();
+
+        assert!(model.add_image_patch(&image, 10, 9));
+        assert!(!model.add_image_patch(&image, 3, 9));
+        assert!(!model.add_image_patch(&image, 10, 20));
+        assert_eq!(model.sample_count(), 1);
+
+        let mut expected = PatchModel::new();
+        expected.add_normalised_sample(sample_patch(&image, 10, 9).unwrap());
+        assert_eq!(model.hists, expected.hists);
+    }
+
+    #[test]
+    fn test_add_sample_as_ref() {
+        let mut rng = StdRng::seed_from_u64(86);
+        let patch = random_patch(&mut rng);
+        let mut expected = PatchModel::new();
+        expected.add_sample(patch);
+        expected.add_normalised_sample(patch);
+
+        let vec = patch.to_vec();
+        let mut from_vec = PatchModel::new();
+        from_vec.add_sample(&vec);
+        from_vec.add_normalised_sample(&vec);
+        assert_eq!(from_vec.hists, expected.hists);
+
+        let mut buffer = [0u8; 100];
+        buffer[20..84].copy_from_slice(&patch);
+        let mut from_slice = PatchModel::new();
+        from_slice.add_sample(&buffer[20..84]);
+        from_slice.add_normalised_sample(&buffer[20..84]);
+        assert_eq!(from_slice.hists, expected.hists);
+    }
+
+    #[test]
+    #[should_panic(expected = "expected a patch of 64 values, found 63")]
+    fn test_add_sample_as_ref_wrong_length() {
+        let mut model = PatchModel::new();
+        model.add_sample(vec![0u8; 63]);
+    }
+
+    #[test]
+    fn test_try_add_normalised_sample() {
+        let mut model = PatchModel::new();
+        assert_eq!(model.try_add_normalised_sample(&[17u8; 64]), Err(VisionError::FlatPatch));
+        assert_eq!(model.sample_count(), 0);
+
+        let mut patch = [0u8; 64];
+        patch[0] = 1;
+        assert_eq!(model.try_add_normalised_sample(&patch), Ok(()));
+        assert_eq!(model.sample_count(), 1);
     }
 }
@@ -1,6 +1,23 @@
 
-extern crate stats;
 extern crate image;
 extern crate imageproc;
+#[cfg(feature = "rayon")]
+extern crate rayon;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(test)]
+extern crate rand;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
 
+pub mod bktree;
+pub mod db;
+pub mod error;
 pub mod imagematch;
+pub mod matcher;
+
+pub use bktree::BkTree;
+pub use db::{DescriptorDb,mutual_best};
+pub use error::VisionError;
+pub use imagematch::*;
+pub use matcher::{Matcher,MatcherConfig};
@@ -0,0 +1,198 @@
+//! A single entry point for matching a trained model against images
+//! using a chosen set of sampling and quantisation parameters.
+
+use image::{GenericImageView,ImageBuffer,Luma};
+use error::VisionError;
+use imagematch::{
+    discrepancy_words,
+    normalise,
+    quantise_patch_bins,
+    sample_patch_reflect_strided,
+    sample_patch_strided,
+    Discrepancy,
+    PatchModel
+};
+
+/// Parameters controlling how a Matcher samples and quantises patches.
+/// The defaults match the free functions in imagematch: 5 bins, a rare
+/// cutoff of 5%, a stride of 1 and no sampling beyond the image borders.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MatcherConfig {
+    bins: usize,
+    rare_cutoff: f32,
+    stride: u32,
+    reflect_borders: bool
+}
+
+impl MatcherConfig {
+
+    /// Create a configuration with the default parameters.
+    pub fn new() -> MatcherConfig {
+        MatcherConfig { bins: 5, rare_cutoff: 0.05, stride: 1, reflect_borders: false }
+    }
+
+    /// Set the number of intensity bins per location.
+    ///
+    /// Panics if bins is zero.
+    pub fn bins(mut self, bins: usize) -> MatcherConfig {
+        assert!(bins > 0, "a matcher needs at least one bin");
+        self.bins = bins;
+        self
+    }
+
+    /// Set the fraction of samples below which a bin is rare.
+    pub fn rare_cutoff(mut self, rare_cutoff: f32) -> MatcherConfig {
+        self.rare_cutoff = rare_cutoff;
+        self
+    }
+
+    /// Set the spacing of sampled pixels, as in sample_patch_strided.
+    ///
+    /// Panics if stride is zero.
+    pub fn stride(mut self, stride: u32) -> MatcherConfig {
+        assert!(stride > 0, "a matcher needs a stride of at least one");
+        self.stride = stride;
+        self
+    }
+
+    /// If true, patches near the image borders are sampled by
+    /// reflecting coordinates back into the image, so every pixel
+    /// is scored. Otherwise such pixels are not scored.
+    pub fn reflect_borders(mut self, reflect_borders: bool) -> MatcherConfig {
+        self.reflect_borders = reflect_borders;
+        self
+    }
+
+    /// Create a matcher for a trained model using this configuration.
+    /// Returns an error if the model does not have the configured
+    /// number of bins or the rare cutoff is not in 0.0..=1.0.
+    pub fn build<const B: usize>(self, model: &PatchModel<64, B>) -> Result<Matcher, VisionError> {
+        if B != self.bins {
+            return Err(VisionError::BinCountMismatch { expected: self.bins, actual: B });
+        }
+        let descriptor = model.quantise_words_with_threshold(self.rare_cutoff)?;
+        Ok(Matcher { config: self, descriptor })
+    }
+}
+
+impl Default for MatcherConfig {
+    fn default() -> MatcherConfig {
+        MatcherConfig::new()
+    }
+}
+
+/// A quantised model together with the parameters used to
+/// sample and quantise the patches it is compared against.
+#[derive(Clone, Debug)]
+pub struct Matcher {
+    config: MatcherConfig,
+    descriptor: Vec<u64>
+}
+
+impl Matcher {
+
+    /// The configuration this matcher was built with.
+    pub fn config(&self) -> &MatcherConfig {
+        &self.config
+    }
+
+    /// The quantised model, in the layout of PatchModel::quantise_words.
+    pub fn descriptor(&self) -> &[u64] {
+        &self.descriptor
+    }
+
+    /// Returns the discrepancy between the model and the patch centred
+    /// at (x, y), or None if the patch cannot be sampled.
+    pub fn score_at<I>(&self, image: &I, x: u32, y: u32) -> Option<Discrepancy>
+        where I: GenericImageView<Pixel=Luma<u8>> {
+
+        let (width, height) = image.dimensions();
+        let sample = if self.config.reflect_borders {
+            if x >= width || y >= height {
+                return None;
+            }
+            sample_patch_reflect_strided(image, x, y, self.config.stride)
+        } else {
+            sample_patch_strided(image, x, y, self.config.stride)?
+        };
+        let patch = quantise_patch_bins(&normalise(&sample), self.config.bins);
+        Some(Discrepancy(discrepancy_words(&patch, &self.descriptor)))
+    }
+
+    /// Computes the discrepancy at each pixel of an image. Pixels
+    /// which cannot be scored are given the value u16::MAX.
+    pub fn match_image<I>(&self, image: &I) -> ImageBuffer<Luma<u16>, Vec<u16>>
+        where I: GenericImageView<Pixel=Luma<u8>> {
+
+        let (width, height) = image.dimensions();
+        ImageBuffer::from_fn(width, height, |x, y| {
+            Luma([self.score_at(image, x, y).map_or(u16::MAX, |d| d.0)])
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use error::VisionError;
+    use imagematch::{
+        discrepancy_words,
+        normalise,
+        quantise_patch_bins,
+        sample_patch_strided,
+        Discrepancy,
+        PatchModel
+    };
+    use imagematch::test::planted_image;
+    use super::MatcherConfig;
+
+    #[test]
+    fn test_matcher_with_bins_and_stride() {
+        let image = planted_image(60, 50, &[(30, 25)]);
+        let mut model = PatchModel::<64, 8>::default();
+        model.add_normalised_sample(sample_patch_strided(&image, 30, 25, 2).unwrap());
+
+        let matcher = MatcherConfig::new().bins(8).stride(2).rare_cutoff(0.1).build(&model).unwrap();
+        assert_eq!(matcher.descriptor(), &model.quantise_words_with_threshold(0.1).unwrap()[..]);
+        assert_eq!(matcher.score_at(&image, 30, 25), Some(Discrepancy(0)));
+        assert_eq!(matcher.score_at(&image, 10, 25), None);
+
+        let scores = matcher.match_image(&image);
+        for &(x, y) in &[(14, 14), (20, 30), (45, 35)] {
+            let sample = sample_patch_strided(&image, x, y, 2).unwrap();
+            let patch = quantise_patch_bins(&normalise(&sample), 8);
+            let expected = discrepancy_words(&patch, matcher.descriptor());
+            assert_eq!(matcher.score_at(&image, x, y), Some(Discrepancy(expected)));
+            assert_eq!(scores.get_pixel(x, y)[0], expected);
+        }
+        assert_eq!(scores.get_pixel(0, 0)[0], u16::MAX);
+    }
+
+    #[test]
+    fn test_matcher_reflect_borders() {
+        let image = planted_image(60, 50, &[(30, 25)]);
+        let mut model = PatchModel::new();
+        model.add_normalised_sample(sample_patch_strided(&image, 30, 25, 1).unwrap());
+
+        let matcher = MatcherConfig::new().reflect_borders(true).build(&model).unwrap();
+        let scores = matcher.match_image(&image);
+        assert!(scores.pixels().all(|p| p[0] <= 64));
+        assert_eq!(scores.get_pixel(30, 25)[0], 0);
+        assert_eq!(matcher.score_at(&image, 60, 0), None);
+    }
+
+    #[test]
+    fn test_matcher_config_errors() {
+        let model = PatchModel::new();
+        assert_eq!(MatcherConfig::new().bins(8).build(&model).unwrap_err(),
+            VisionError::BinCountMismatch { expected: 8, actual: 5 });
+        assert_eq!(MatcherConfig::new().rare_cutoff(1.5).build(&model).unwrap_err(),
+            VisionError::InvalidThreshold(1.5));
+    }
+
+    #[test]
+    #[should_panic(expected = "a matcher needs a stride of at least one")]
+    fn test_matcher_zero_stride() {
+        MatcherConfig::new().stride(0);
+    }
+}